@@ -3,20 +3,31 @@
 /// or https://blog.ipfs.io/2022-01-20-libp2p-hole-punching/
 /// for concrete guide of usage.
 
-use futures::join;
-use std::io::Write;
+use futures::{future, select, FutureExt};
+use futures::stream::StreamExt;
+use std::collections::BTreeSet;
 use std::process::exit;
-use std::time::Duration;
-use log::{info, warn};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use log::{info, warn, error};
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, Helper};
 
-use async_std::task::block_on;
-use async_std::task;
+use async_std::task::{self, block_on};
+use async_trait::async_trait;
 use async_std::channel;
 
 use p2p_demo::conf::Conf;
-use p2p_demo::Node;
+#[cfg(unix)]
+use p2p_demo::control;
+use p2p_demo::{Command, CommandError, CommandRegistry, CommandSpec, CustomCommand, Node, NodeEvent, Outcome};
 
 const CONFIG_PATH: &str = "node.ini";
+const HISTORY_PATH: &str = ".p2p_demo_history";
 
 fn main() {
     std::env::set_var("RUST_BACKTRACE", "1");
@@ -25,62 +36,181 @@ fn main() {
     }
     env_logger::init();
 
-    block_on(async_main());
+    if let Err(err) = block_on(async_main()) {
+        error!("{}", err);
+        exit(1);
+    }
 }
 
-async fn async_main() {
-    let conf = Conf::new(CONFIG_PATH);
-    let role = conf.role.clone();
+async fn async_main() -> p2p_demo::Result<()> {
+    let conf = Conf::new(CONFIG_PATH)?;
     conf.print_detail();
+    let control_socket = conf.get_control_socket().map(str::to_string);
 
-    let node = Node::new(conf);
+    let node = Node::new(conf)?;
     info!("Local peer id: {:?}", node.get_peer_id());
 
-    node.bind().await;
+    node.bind().await?;
+    match node.connect_hub().await {
+        Ok(relays) => for relay_id in relays {
+            info!("Reserved on hub {relay_id:?}");
+        },
+        // Not fatal, `relay` can be retried from the prompt
+        Err(err) => warn!("Reserving on the hub failed: {err}"),
+    }
+
+    let mut registry = CommandRegistry::new();
+    registry.register(WhoAmI);
+    let specs = registry.specs(&node);
+
+    let (sender, receiver) = channel::bounded(1);
+    let peers = Arc::new(Mutex::new(BTreeSet::new()));
+
+    // Remember who we met for completion
+    let mut events = node.subscribe().await?;
+    let known = peers.clone();
+    task::spawn(async move {
+        while let Some(event) = events.next().await {
+            let mut known = known.lock().expect("Completion lock poisoned");
+            match event {
+                NodeEvent::PeerIdentified { peer_id, username, .. } => {
+                    known.insert(peer_id.to_string());
+                    known.extend(username);
+                }
+                NodeEvent::ConnectionEstablished { peer_id, .. } => {
+                    known.insert(peer_id.to_string());
+                }
+                _ => {}
+            }
+        }
+    });
+
+    // Line editing blocks, so it gets a thread of its own
+    thread::spawn(move || {
+        if let Err(err) = repl(sender, ReplHelper { specs, peers }) {
+            error!("Prompt failed: {err}");
+        }
+    });
 
-    if let "hub" = role.as_str() {
-        wait_response(&node).await
+    let prompt = async {
+        // `None` once the prompt is gone
+        while let Ok(Some(user_input)) = receiver.recv().await {
+            match registry.execute(&node, &user_input).await {
+                Ok(Outcome::Quit) => break,
+                Ok(Outcome::Continue) => {},
+                Ok(Outcome::Output(output)) => println!("{output}"),
+                Err(err) => warn!("{}", err),
+            }
+        }
+        if let Err(err) = node.shutdown().await {
+            warn!("{}", err);
+        }
+    };
+
+    // `Conf` refuses `control_socket` where there are no Unix sockets
+    let control = async {
+        #[cfg(unix)]
+        if let Some(path) = &control_socket {
+            if let Err(err) = control::serve(&node, &registry, path).await {
+                error!("Control API failed: {err}");
+            }
+        }
+        future::pending::<()>().await
+    };
+
+    // `quit` over the control socket stops the node while the prompt waits for input
+    select! {
+        _ = prompt.fuse() => {},
+        _ = control.fuse() => {},
+        _ = node.wait().fuse() => {},
     }
-    else {
-        let (sender, receiver) = channel::bounded(1);
+    node.wait().await;
+    if let Some(path) = control_socket {
+        let _ = std::fs::remove_file(path);
+    }
+    Ok(())
+}
 
-        // sets handler which respondes to user input in a spawned process
-        ctrlc::set_handler(move || {
-            if sender.is_closed() || sender.is_full() {
-                return;
+/// Reads commands until quit, Ctrl-C or Ctrl-D, the latter two ending the input with `None`.
+fn repl(sender: channel::Sender<Option<String>>, helper: ReplHelper) -> rustyline::Result<()> {
+    let specs = helper.specs.clone();
+    let mut editor = Editor::<ReplHelper>::new()?;
+    editor.set_helper(Some(helper));
+    // No history yet on the first run
+    let _ = editor.load_history(HISTORY_PATH);
+
+    loop {
+        let line = match editor.readline("> ") {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted | ReadlineError::Eof) => {
+                let _ = block_on(sender.send(None));
+                break;
             }
-            let mut user_input = String::new();
-            let stdout_handle = std::io::stdout();
-            let mut guard = stdout_handle.lock();
-            guard.write(b"\r> ").expect("Write to stdout failed");
-            guard.flush().expect("Flush stdout failed");
-            std::io::stdin().read_line(&mut user_input).unwrap();
-    
-            block_on(async {
-                sender.send(user_input).await.expect("Channel error")
-            });
-        }).expect("Error setting Ctrl-C handler");
-    
-        let f1 = async {
-            loop {
-                if let Ok(user_input) = receiver.recv().await {
-                    match node.execute(user_input).await {
-                        Ok(true) => break,
-                        Ok(false) => task::sleep(Duration::from_micros(100)).await,
-                        Err(err) => warn!("{}", err),
-                    }
-                }
+            Err(err) => {
+                let _ = block_on(sender.send(None));
+                return Err(err);
             }
-            exit(0);
         };
-        let f2 = wait_response(&node);
-        join!(f1, f2);
+        if !line.trim().is_empty() {
+            editor.add_history_entry(line.as_str());
+        }
+        // Spelled however the node spells it, aliases included
+        let quit = matches!(Command::parse(&line, &specs), Ok(Some(Command::Quit)));
+        if block_on(sender.send(Some(line))).is_err() || quit {
+            break;
+        }
     }
+    editor.save_history(HISTORY_PATH)
 }
 
-async fn wait_response(node: &Node) {
-    loop {
-        task::sleep(Duration::from_micros(100)).await;
-        node.wait().await;
+/// Completes command names, then PeerIds and usernames seen so far.
+struct ReplHelper {
+    specs: Vec<CommandSpec>,
+    peers: Arc<Mutex<BTreeSet<String>>>,
+}
+
+impl Completer for ReplHelper {
+    type Candidate = String;
+
+    fn complete(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> rustyline::Result<(usize, Vec<String>)> {
+        let start = line[..pos].char_indices().rev()
+            .find(|(_, c)| c.is_whitespace())
+            .map_or(0, |(i, c)| i + c.len_utf8());
+        let word = &line[start..pos];
+        let candidates = if start == 0 {
+            self.specs.iter()
+                .map(|spec| spec.name)
+                .filter(|name| name.starts_with(word))
+                .map(str::to_string)
+                .collect()
+        } else {
+            let peers = self.peers.lock().expect("Completion lock poisoned");
+            peers.iter().filter(|peer| peer.starts_with(word)).cloned().collect()
+        };
+        Ok((start, candidates))
+    }
+}
+
+impl Hinter for ReplHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ReplHelper {}
+
+impl Validator for ReplHelper {}
+
+impl Helper for ReplHelper {}
+
+/// Example of a command added by the application
+struct WhoAmI;
+
+#[async_trait]
+impl CustomCommand for WhoAmI {
+    fn spec(&self) -> CommandSpec {
+        CommandSpec { name: "whoami", alias: None, usage: "", help: "print our PeerId" }
+    }
+
+    async fn run(&self, node: &Node, _args: &str) -> Result<Outcome, CommandError> {
+        Ok(Outcome::Output(node.get_peer_id().to_string()))
     }
 }
@@ -0,0 +1,135 @@
+/// Allowlist and denylist deciding which peers may use the hub
+
+use libp2p::PeerId;
+use std::collections::HashSet;
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::SystemTime;
+
+use crate::error::{Error, Result};
+
+/// Denied peers are always refused. When the allowlist isn't empty, only
+/// peers on it are accepted, otherwise everybody not denied is.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct AccessList {
+    allow: HashSet<PeerId>,
+    deny: HashSet<PeerId>,
+}
+
+impl AccessList {
+    pub fn new(allow: impl IntoIterator<Item = PeerId>, deny: impl IntoIterator<Item = PeerId>) -> Self {
+        Self {
+            allow: allow.into_iter().collect(),
+            deny: deny.into_iter().collect(),
+        }
+    }
+
+    /// Parses an ACL file, one `allow <peerid>` or `deny <peerid>` per line,
+    /// blank lines and lines starting with `#` are skipped.
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut acl = Self::default();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = || Error::Config(format!("ACL line {}: {line:?}, expected `allow|deny <peerid>`", number + 1));
+            let (rule, peer) = line.split_once(char::is_whitespace).ok_or_else(invalid)?;
+            let peer_id = PeerId::from_str(peer.trim()).map_err(|_| invalid())?;
+            match rule {
+                "allow" => acl.allow.insert(peer_id),
+                "deny" => acl.deny.insert(peer_id),
+                _ => return Err(invalid()),
+            };
+        }
+        Ok(acl)
+    }
+
+    /// Rules of both lists together.
+    pub fn merge(mut self, other: &AccessList) -> Self {
+        self.allow.extend(other.allow.iter().copied());
+        self.deny.extend(other.deny.iter().copied());
+        self
+    }
+
+    pub fn is_allowed(&self, peer_id: &PeerId) -> bool {
+        !self.deny.contains(peer_id) && (self.allow.is_empty() || self.allow.contains(peer_id))
+    }
+
+    pub fn is_denied(&self, peer_id: &PeerId) -> bool {
+        self.deny.contains(peer_id)
+    }
+
+    pub fn denied(&self) -> impl Iterator<Item = &PeerId> {
+        self.deny.iter()
+    }
+}
+
+/// Config rules plus an optional ACL file, reloaded whenever it changes
+pub(crate) struct AclWatch {
+    base: AccessList,
+    path: Option<PathBuf>,
+    modified: Option<SystemTime>,
+}
+
+impl AclWatch {
+    pub(crate) fn new(base: AccessList, path: Option<PathBuf>) -> Self {
+        Self { base, path, modified: None }
+    }
+
+    pub(crate) fn is_watching(&self) -> bool {
+        self.path.is_some()
+    }
+
+    /// Current rules, `None` if the file didn't change since the last load
+    /// and `force` isn't set.
+    pub(crate) fn load(&mut self, force: bool) -> Result<Option<AccessList>> {
+        let path = match &self.path {
+            Some(path) => path,
+            None if force => return Ok(Some(self.base.clone())),
+            None => return Ok(None),
+        };
+        let modified = fs::metadata(path).and_then(|meta| meta.modified()).map_err(Error::Io)?;
+        if !force && self.modified == Some(modified) {
+            return Ok(None);
+        }
+        let text = fs::read_to_string(path).map_err(Error::Io)?;
+        let acl = AccessList::parse(&text)?.merge(&self.base);
+        self.modified = Some(modified);
+        Ok(Some(acl))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AccessList;
+    use libp2p::PeerId;
+
+    #[test]
+    fn deny_wins_over_allow() {
+        let (alice, bob, carol) = (PeerId::random(), PeerId::random(), PeerId::random());
+
+        let open = AccessList::new([], [bob]);
+        assert!(open.is_allowed(&alice));
+        assert!(!open.is_allowed(&bob));
+
+        let closed = AccessList::new([alice, bob], [bob]);
+        assert!(closed.is_allowed(&alice));
+        assert!(!closed.is_allowed(&bob));
+        assert!(!closed.is_allowed(&carol));
+        // Unlisted peers are only disconnected, denied ones get banned
+        assert!(!closed.is_denied(&carol));
+        assert!(closed.is_denied(&bob));
+    }
+
+    #[test]
+    fn parse_file() {
+        let (alice, bob) = (PeerId::random(), PeerId::random());
+        let text = format!("# hub acl\nallow {alice}\n\n  deny   {bob}\n");
+        assert_eq!(AccessList::parse(&text).unwrap(), AccessList::new([alice], [bob]));
+
+        assert!(AccessList::parse("permit 12D3KooW").is_err());
+        assert!(AccessList::parse("allow not-a-peer-id").is_err());
+    }
+}
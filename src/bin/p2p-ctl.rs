@@ -0,0 +1,88 @@
+/// Talks to the control socket of a running `p2p-node`, printing the JSON response.
+/// Unix only, like the socket.
+
+#[cfg(unix)]
+use clap::{Parser, Subcommand};
+#[cfg(unix)]
+use std::io::{BufRead, BufReader, Write};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::process::exit;
+
+#[cfg(unix)]
+use p2p_demo::control::{ControlRequest, ControlResponse};
+
+#[cfg(unix)]
+#[derive(Debug, Parser)]
+#[clap(about = "Scripts a running node through its control socket")]
+struct Args {
+    /// `control_socket` of the node
+    #[clap(short, long, default_value = "p2p_demo.sock")]
+    socket: String,
+    #[clap(subcommand)]
+    request: Request,
+}
+
+#[cfg(unix)]
+#[derive(Debug, Subcommand)]
+enum Request {
+    /// PeerId, listen addresses and connected peers
+    Status,
+    /// Run a prompt command, e.g. `exec dial bob`
+    Exec {
+        #[clap(required = true)]
+        line: Vec<String>,
+    },
+    /// Clients registered on the hub directory
+    DirectoryPeers,
+    /// Whether connected peers are relayed or direct, clients only
+    PeerConnections,
+    /// Reservations on a hub
+    Reservations,
+    /// Circuits relayed by a hub
+    Circuits,
+}
+
+#[cfg(not(unix))]
+fn main() {
+    eprintln!("p2p-ctl needs Unix sockets, unavailable on this platform");
+    exit(2);
+}
+
+#[cfg(unix)]
+fn main() {
+    let args = Args::parse();
+    let request = match args.request {
+        Request::Status => ControlRequest::Status,
+        Request::Exec { line } => ControlRequest::Execute { line: line.join(" ") },
+        Request::DirectoryPeers => ControlRequest::DirectoryPeers,
+        Request::PeerConnections => ControlRequest::PeerConnections,
+        Request::Reservations => ControlRequest::Reservations,
+        Request::Circuits => ControlRequest::Circuits,
+    };
+    match call(&args.socket, &request) {
+        Ok(response) => {
+            println!("{response}");
+            if let Ok(ControlResponse::Error { .. }) = serde_json::from_str(&response) {
+                exit(1);
+            }
+        }
+        Err(err) => {
+            eprintln!("{}: {err}", args.socket);
+            exit(2);
+        }
+    }
+}
+
+/// Sends `request` and returns the response line as is.
+#[cfg(unix)]
+fn call(socket: &str, request: &ControlRequest) -> std::io::Result<String> {
+    let mut stream = UnixStream::connect(socket)?;
+    let mut json = serde_json::to_string(request)?;
+    json.push('\n');
+    stream.write_all(json.as_bytes())?;
+
+    let mut response = String::new();
+    BufReader::new(stream).read_line(&mut response)?;
+    Ok(response.trim_end().to_string())
+}
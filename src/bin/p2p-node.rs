@@ -0,0 +1,166 @@
+/// Headless node, configured by file and command line, stopped by SIGINT or
+/// SIGTERM, SIGHUP reloads the hub ACL. Suited to systemd or containers, use
+/// `examples/node.rs` for the prompt.
+
+use clap::Parser;
+use futures::future::{self, FutureExt};
+use futures::select;
+#[cfg(unix)]
+use futures::stream::StreamExt;
+use futures_timer::Delay;
+use log::{info, warn, error};
+#[cfg(unix)]
+use signal_hook::consts::signal::{SIGHUP, SIGINT, SIGTERM};
+#[cfg(unix)]
+use signal_hook_async_std::Signals;
+use std::process::exit;
+use std::time::Duration;
+
+use async_std::task::block_on;
+
+use p2p_demo::conf::Conf;
+#[cfg(unix)]
+use p2p_demo::{control, CommandRegistry};
+use p2p_demo::{Error, Node};
+
+/// Delay between attempts to dial a `--dial` target
+const DIAL_RETRY: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Parser)]
+#[clap(about = "Runs a hub or client without a prompt")]
+struct Args {
+    /// Config file
+    #[clap(short, long, default_value = "node.ini")]
+    config: String,
+    /// `hub` or `client`, overrides `role`
+    #[clap(short, long)]
+    role: Option<String>,
+    /// Hub multiaddr, e.g. `/dns4/relay.example.com/tcp/8443/p2p/<id>`, overrides `hub_addr`
+    #[clap(long)]
+    hub: Option<String>,
+    /// Reserve on the configured hubs at startup
+    #[clap(long)]
+    auto_relay: bool,
+    /// PeerId or username to dial once reserved, may be repeated
+    #[clap(short, long = "dial")]
+    dial: Vec<String>,
+}
+
+fn main() {
+    if let Err(_) = std::env::var("RUST_LOG") {
+        std::env::set_var("RUST_LOG", "info");
+    }
+    env_logger::init();
+
+    if let Err(err) = block_on(async_main(Args::parse())) {
+        error!("{}", err);
+        exit(1);
+    }
+}
+
+async fn async_main(args: Args) -> p2p_demo::Result<()> {
+    let mut overrides = Vec::new();
+    if let Some(role) = args.role {
+        overrides.push(("role", role));
+    }
+    if let Some(hub) = args.hub {
+        overrides.push(("client.hub_addr", hub));
+    }
+    let conf = Conf::with_overrides(&args.config, overrides)?;
+    conf.print_detail();
+    let control_socket = conf.get_control_socket().map(str::to_string);
+
+    let node = Node::new(conf)?;
+    info!("Local peer id: {:?}", node.get_peer_id());
+    node.bind().await?;
+
+    let unattended = async {
+        if args.auto_relay {
+            match node.connect_hub().await {
+                Ok(relays) => for relay_id in relays {
+                    info!("Reserved on hub {relay_id:?}");
+                },
+                // The client keeps reconnecting to its hubs on its own
+                Err(err) => warn!("Reserving on the hubs failed: {err}"),
+            }
+        }
+        future::join_all(args.dial.iter().map(|target| dial(&node, target))).await;
+        future::pending::<()>().await
+    };
+
+    // `Conf` refuses `control_socket` where there are no Unix sockets
+    let control = async {
+        #[cfg(unix)]
+        if let Some(path) = &control_socket {
+            if let Err(err) = control::serve(&node, &CommandRegistry::new(), path).await {
+                error!("Control API failed: {err}");
+            }
+        }
+        future::pending::<()>().await
+    };
+
+    select! {
+        _ = unattended.fuse() => {},
+        _ = control.fuse() => {},
+        result = handle_signals(&node).fuse() => {
+            result?;
+            node.shutdown().await?;
+        },
+        _ = node.wait().fuse() => {},
+    }
+    node.wait().await;
+    if let Some(path) = control_socket {
+        let _ = std::fs::remove_file(path);
+    }
+    Ok(())
+}
+
+/// Returns once asked to shut down, reloading the hub ACL on SIGHUP meanwhile.
+#[cfg(unix)]
+async fn handle_signals(node: &Node) -> p2p_demo::Result<()> {
+    let mut signals = Signals::new([SIGINT, SIGTERM, SIGHUP]).map_err(Error::Io)?;
+    while let Some(signal) = signals.next().await {
+        if signal != SIGHUP {
+            info!("Received signal {signal}, shutting down");
+            break;
+        }
+        match node {
+            Node::Hub(hub) => match hub.reload_acl().await {
+                Ok(()) => info!("Reloaded the ACL on SIGHUP"),
+                Err(err) => error!("Reloading the ACL failed, keeping the previous rules: {err}"),
+            },
+            Node::Client(_) => info!("Received SIGHUP, clients have nothing to reload"),
+        }
+    }
+    signals.handle().close();
+    Ok(())
+}
+
+/// Signals are unix only, elsewhere Ctrl-C ends the process as usual.
+#[cfg(not(unix))]
+async fn handle_signals(_node: &Node) -> p2p_demo::Result<()> {
+    future::pending().await
+}
+
+/// Dials `target` until it succeeds, retrying every `DIAL_RETRY`.
+async fn dial(node: &Node, target: &str) {
+    loop {
+        let result = match node.resolve(target).await {
+            Ok(peer_id) => node.dial(peer_id).await,
+            Err(err) => Err(err),
+        };
+        match result {
+            Ok(()) => {
+                info!("Connected to {target}");
+                return;
+            }
+            Err(Error::Shutdown) => return,
+            Err(err @ Error::Config(_)) => {
+                warn!("Not dialing {target}: {err}");
+                return;
+            }
+            Err(err) => warn!("Dialing {target} failed, retrying in {DIAL_RETRY:?}: {err}"),
+        }
+        Delay::new(DIAL_RETRY).await;
+    }
+}
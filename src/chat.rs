@@ -0,0 +1,18 @@
+/// Direct text messages between connected clients
+
+use serde::{Deserialize, Serialize};
+
+use crate::codec::JsonCodec;
+
+pub(crate) const CHAT_PROTOCOL: &[u8] = b"/p2p_demo/chat/1.0.0";
+
+pub(crate) type ChatCodec = JsonCodec<ChatRequest, ChatResponse>;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatRequest {
+    pub text: String,
+}
+
+/// Acknowledges a delivered `ChatRequest`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatResponse;
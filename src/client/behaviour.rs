@@ -14,8 +14,16 @@ use libp2p::{
 };
 use libp2p::relay::v2::client::Client;
 use libp2p::dcutr::behaviour::Behaviour as Dcutr;
+use libp2p::request_response::{ProtocolSupport, RequestResponse};
+use std::iter;
 
+use super::connections::Connections;
 use crate::Event;
+use crate::codec::Protocol;
+use crate::directory::{DirectoryCodec, DIRECTORY_PROTOCOL};
+use crate::chat::{ChatCodec, CHAT_PROTOCOL};
+use crate::transfer::{FileCodec, FILE_PROTOCOL};
+use crate::directory::agent_version;
 
 #[derive(NetworkBehaviour)]
 #[behaviour(out_event = "Event", event_process = false)]
@@ -24,18 +32,38 @@ pub struct Behaviour {
     identify: Identify,
     relay_client: Client,
     dcutr: Dcutr,
+    pub(crate) directory: RequestResponse<DirectoryCodec>,
+    pub(crate) chat: RequestResponse<ChatCodec>,
+    pub(crate) files: RequestResponse<FileCodec>,
+    pub(crate) connections: Connections,
 }
 
 impl Behaviour {
-    pub fn new(public_key: PublicKey, client: Client) -> Self {
+    pub fn new(public_key: PublicKey, client: Client, username: Option<&str>, close_relayed: bool) -> Self {
         Self {
             ping: Ping::new(PingConfig::new().with_keep_alive(true)),
             identify: Identify::new(IdentifyConfig::new(
                 "/TODO/0.0.1".to_string(),
                 public_key,
-            )),
+            ).with_agent_version(agent_version(username))),
             relay_client: client,
             dcutr: Dcutr::new(),
+            directory: RequestResponse::new(
+                DirectoryCodec::default(),
+                iter::once((Protocol(DIRECTORY_PROTOCOL), ProtocolSupport::Outbound)),
+                Default::default(),
+            ),
+            chat: RequestResponse::new(
+                ChatCodec::default(),
+                iter::once((Protocol(CHAT_PROTOCOL), ProtocolSupport::Full)),
+                Default::default(),
+            ),
+            files: RequestResponse::new(
+                FileCodec::default(),
+                iter::once((Protocol(FILE_PROTOCOL), ProtocolSupport::Full)),
+                Default::default(),
+            ),
+            connections: Connections::new(close_relayed),
         }
     }
 }
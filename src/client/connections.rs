@@ -0,0 +1,283 @@
+/// Tracks whether peers are reached through a relay or directly, and
+/// closes relayed connections made redundant by hole punching
+
+use libp2p::core::connection::ConnectionId;
+use libp2p::core::ConnectedPoint;
+use libp2p::swarm::handler::DummyConnectionHandler;
+use libp2p::swarm::{
+    CloseConnection, ConnectionHandler, IntoConnectionHandler,
+    NetworkBehaviour, NetworkBehaviourAction, PollParameters,
+};
+use libp2p::{Multiaddr, PeerId};
+use std::collections::{HashMap, VecDeque};
+use std::convert::Infallible;
+use std::fmt;
+use std::task::{Context, Poll, Waker};
+
+/// How we reach a peer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// Through a relay circuit only.
+    Relayed,
+    /// Hole punching is under way, still relayed.
+    Upgrading,
+    /// Over a direct connection, relayed ones may linger until they close.
+    Direct,
+    /// Hole punching failed, still relayed.
+    Failed { reason: String },
+}
+
+impl fmt::Display for ConnectionState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectionState::Relayed => write!(f, "relayed"),
+            ConnectionState::Upgrading => write!(f, "upgrading"),
+            ConnectionState::Direct => write!(f, "direct"),
+            ConnectionState::Failed { reason } => write!(f, "failed ({reason})"),
+        }
+    }
+}
+
+/// A connected peer and how we reach it
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerConnection {
+    pub peer_id: PeerId,
+    /// Username it advertises, if we learned it
+    pub username: Option<String>,
+    pub state: ConnectionState,
+}
+
+#[derive(Default)]
+struct Tracked {
+    relayed: Vec<ConnectionId>,
+    direct: usize,
+    /// Outcome of the last hole punch, `None` while none was tried
+    upgrade: Option<ConnectionState>,
+}
+
+impl Tracked {
+    fn state(&self) -> ConnectionState {
+        if self.direct > 0 {
+            return ConnectionState::Direct;
+        }
+        match &self.upgrade {
+            Some(state @ (ConnectionState::Upgrading | ConnectionState::Failed { .. })) => state.clone(),
+            // A direct connection that since closed leaves us relayed again
+            _ => ConnectionState::Relayed,
+        }
+    }
+}
+
+/// Behaviour without protocol of its own, it only watches connections
+pub struct Connections {
+    peers: HashMap<PeerId, Tracked>,
+    close_relayed: bool,
+    to_close: VecDeque<(PeerId, ConnectionId)>,
+    /// Of the last `poll` that found nothing to close
+    waker: Option<Waker>,
+}
+
+impl Connections {
+    /// With `close_relayed`, relayed connections to a peer are closed once
+    /// a direct one is up, along with requests still running over them.
+    pub fn new(close_relayed: bool) -> Self {
+        Self { peers: HashMap::new(), close_relayed, to_close: VecDeque::new(), waker: None }
+    }
+
+    pub(crate) fn states(&self) -> impl Iterator<Item = (PeerId, ConnectionState)> + '_ {
+        self.peers.iter().map(|(peer_id, tracked)| (*peer_id, tracked.state()))
+    }
+
+    pub(crate) fn upgrade_started(&mut self, peer_id: PeerId) {
+        if let Some(tracked) = self.peers.get_mut(&peer_id) {
+            tracked.upgrade = Some(ConnectionState::Upgrading);
+        }
+    }
+
+    pub(crate) fn upgrade_succeeded(&mut self, peer_id: PeerId) {
+        if let Some(tracked) = self.peers.get_mut(&peer_id) {
+            tracked.upgrade = Some(ConnectionState::Direct);
+        }
+        self.close_redundant(peer_id);
+    }
+
+    pub(crate) fn upgrade_failed(&mut self, peer_id: PeerId, reason: String) {
+        if let Some(tracked) = self.peers.get_mut(&peer_id) {
+            tracked.upgrade = Some(ConnectionState::Failed { reason });
+        }
+    }
+
+    fn close_redundant(&mut self, peer_id: PeerId) {
+        let tracked = match self.peers.get_mut(&peer_id) {
+            Some(tracked) if self.close_relayed && tracked.direct > 0 => tracked,
+            _ => return,
+        };
+        if tracked.relayed.is_empty() {
+            return;
+        }
+        self.to_close.extend(tracked.relayed.drain(..).map(|connection| (peer_id, connection)));
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl NetworkBehaviour for Connections {
+    type ConnectionHandler = DummyConnectionHandler;
+    type OutEvent = Infallible;
+
+    fn new_handler(&mut self) -> Self::ConnectionHandler {
+        DummyConnectionHandler::default()
+    }
+
+    fn inject_connection_established(
+        &mut self,
+        peer_id: &PeerId,
+        connection_id: &ConnectionId,
+        endpoint: &ConnectedPoint,
+        _failed_addresses: Option<&Vec<Multiaddr>>,
+        _other_established: usize,
+    ) {
+        let tracked = self.peers.entry(*peer_id).or_default();
+        if endpoint.is_relayed() {
+            tracked.relayed.push(*connection_id);
+        } else {
+            tracked.direct += 1;
+        }
+        self.close_redundant(*peer_id);
+    }
+
+    fn inject_connection_closed(
+        &mut self,
+        peer_id: &PeerId,
+        connection_id: &ConnectionId,
+        endpoint: &ConnectedPoint,
+        _handler: <Self::ConnectionHandler as IntoConnectionHandler>::Handler,
+        remaining_established: usize,
+    ) {
+        if remaining_established == 0 {
+            self.peers.remove(peer_id);
+            return;
+        }
+        if let Some(tracked) = self.peers.get_mut(peer_id) {
+            if endpoint.is_relayed() {
+                tracked.relayed.retain(|connection| connection != connection_id);
+            } else {
+                tracked.direct = tracked.direct.saturating_sub(1);
+            }
+        }
+    }
+
+    fn inject_event(
+        &mut self,
+        _peer_id: PeerId,
+        _connection: ConnectionId,
+        _event: <<Self::ConnectionHandler as IntoConnectionHandler>::Handler as ConnectionHandler>::OutEvent,
+    ) {
+    }
+
+    fn poll(
+        &mut self,
+        cx: &mut Context<'_>,
+        _params: &mut impl PollParameters,
+    ) -> Poll<NetworkBehaviourAction<Self::OutEvent, Self::ConnectionHandler>> {
+        match self.to_close.pop_front() {
+            Some((peer_id, connection)) => Poll::Ready(NetworkBehaviourAction::CloseConnection {
+                peer_id,
+                connection: CloseConnection::One(connection),
+            }),
+            None => {
+                self.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ConnectionState, Connections, Tracked};
+    use libp2p::core::connection::{ConnectionId, Endpoint};
+    use libp2p::core::ConnectedPoint;
+    use libp2p::swarm::NetworkBehaviour;
+    use libp2p::PeerId;
+
+    fn dialed(address: &str) -> ConnectedPoint {
+        ConnectedPoint::Dialer {
+            address: address.parse().unwrap(),
+            role_override: Endpoint::Dialer,
+        }
+    }
+
+    fn establish(
+        connections: &mut Connections,
+        peer_id: PeerId,
+        id: usize,
+        endpoint: &ConnectedPoint,
+    ) {
+        let connection = ConnectionId::new(id);
+        connections.inject_connection_established(&peer_id, &connection, endpoint, None, 0);
+    }
+
+    fn relayed(peer_id: PeerId) -> ConnectedPoint {
+        let relay_id = PeerId::random();
+        dialed(&format!("/ip4/10.0.0.1/tcp/8443/p2p/{relay_id}/p2p-circuit/p2p/{peer_id}"))
+    }
+
+    #[test]
+    fn direct_wins_over_upgrade_outcome() {
+        let mut tracked = Tracked::default();
+        assert_eq!(tracked.state(), ConnectionState::Relayed);
+
+        tracked.upgrade = Some(ConnectionState::Upgrading);
+        assert_eq!(tracked.state(), ConnectionState::Upgrading);
+
+        tracked.upgrade = Some(ConnectionState::Direct);
+        tracked.direct = 1;
+        assert_eq!(tracked.state(), ConnectionState::Direct);
+
+        // Direct connection dropped, back to the circuit
+        tracked.direct = 0;
+        assert_eq!(tracked.state(), ConnectionState::Relayed);
+
+        tracked.upgrade = Some(ConnectionState::Failed { reason: "timeout".to_string() });
+        assert_eq!(tracked.state().to_string(), "failed (timeout)");
+    }
+
+    #[test]
+    fn failed_upgrade_stays_relayed() {
+        let peer_id = PeerId::random();
+        let mut connections = Connections::new(true);
+        establish(&mut connections, peer_id, 1, &relayed(peer_id));
+
+        connections.upgrade_started(peer_id);
+        let upgrading = ConnectionState::Upgrading;
+        assert_eq!(connections.states().collect::<Vec<_>>(), [(peer_id, upgrading)]);
+        connections.upgrade_failed(peer_id, "no route".to_string());
+        let failed = ConnectionState::Failed { reason: "no route".to_string() };
+        assert_eq!(connections.states().collect::<Vec<_>>(), [(peer_id, failed)]);
+        assert!(connections.to_close.is_empty());
+    }
+
+    #[test]
+    fn relayed_closed_only_once_direct() {
+        let peer_id = PeerId::random();
+        let mut connections = Connections::new(true);
+        establish(&mut connections, peer_id, 1, &relayed(peer_id));
+
+        // Hole punching reported success, but no direct connection showed up yet
+        connections.upgrade_succeeded(peer_id);
+        assert!(connections.to_close.is_empty());
+
+        establish(&mut connections, peer_id, 2, &dialed("/ip4/192.0.2.7/tcp/4001"));
+        assert_eq!(connections.to_close, [(peer_id, ConnectionId::new(1))]);
+
+        // Without close_relayed both connections stay
+        let mut connections = Connections::new(false);
+        establish(&mut connections, peer_id, 1, &relayed(peer_id));
+        establish(&mut connections, peer_id, 2, &dialed("/ip4/192.0.2.7/tcp/4001"));
+        connections.upgrade_succeeded(peer_id);
+        assert!(connections.to_close.is_empty());
+        assert_eq!(connections.states().collect::<Vec<_>>(), [(peer_id, ConnectionState::Direct)]);
+    }
+}
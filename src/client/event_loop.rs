@@ -0,0 +1,840 @@
+/// Drives the client's swarm from a single task
+
+use libp2p::core::transport::{ListenerId, TransportError};
+use libp2p::multiaddr::Protocol;
+use libp2p::swarm::{Swarm, SwarmEvent};
+use libp2p::swarm::dial_opts::{DialOpts, PeerCondition};
+use libp2p::relay::v2::client::Event as RelayClientEventKinds;
+use libp2p::identify::{IdentifyEvent as IdentifyEventKinds, IdentifyInfo};
+use libp2p::dcutr::behaviour::Event as DcutrEventKinds;
+use libp2p::metrics::Metrics;
+use libp2p::request_response::{RequestId, RequestResponseEvent, RequestResponseMessage};
+use libp2p::{Multiaddr, PeerId};
+use futures::channel::{mpsc, oneshot};
+use futures::future::{Fuse, FutureExt, OptionFuture};
+use futures::stream::StreamExt;
+use futures::select;
+use futures_timer::Delay;
+use log::{info, debug, error, warn};
+use std::collections::{HashMap, HashSet};
+use std::collections::hash_map::Entry;
+use std::fmt::Debug;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use super::behaviour::Behaviour;
+use super::connections::PeerConnection;
+use crate::error::{Error, Result};
+use crate::event::{NodeEvent, Status, Subscribers};
+use crate::metrics;
+use crate::directory::{
+    PeerDirectory, username_from_agent_version,
+    DirectoryEntry, DirectoryRequest, DirectoryResponse,
+};
+use crate::Event::RelayClient as RelayClientEvent;
+use crate::Event::Identify as IdentifyEvent;
+use crate::Event::Ping as PingEvent;
+use crate::Event::Dcutr as DcutrEvent;
+use crate::Event::Directory as DirectoryEvent;
+use crate::Event::Chat as ChatEvent;
+use crate::Event::File as FileEvent;
+use crate::chat::{ChatRequest, ChatResponse};
+use crate::transfer::Transfers;
+
+/// Requests sent from `Client` to its swarm task
+pub(crate) enum Command {
+    Listen {
+        addr: Multiaddr,
+        reply: oneshot::Sender<Result<()>>,
+    },
+    /// Reserve a slot on the relay at `addr` and listen through it.
+    Relay {
+        relay_id: PeerId,
+        addr: Multiaddr,
+        reply: oneshot::Sender<Result<()>>,
+    },
+    /// Dial `peer_id` through every reserved relay, resolves on the first circuit established.
+    Dial {
+        peer_id: PeerId,
+        reply: oneshot::Sender<Result<()>>,
+    },
+    /// Send `text` to `peer_id`, resolves once the peer acknowledged it.
+    SendMessage {
+        peer_id: PeerId,
+        text: String,
+        reply: oneshot::Sender<Result<()>>,
+    },
+    /// Offer the file at `path`, resolves with its transfer id once acknowledged.
+    SendFile {
+        peer_id: PeerId,
+        path: PathBuf,
+        sha256: String,
+        size: u64,
+        reply: oneshot::Sender<Result<u64>>,
+    },
+    /// Start or resume receiving an offered file.
+    AcceptFile {
+        transfer_id: u64,
+        reply: oneshot::Sender<Result<()>>,
+    },
+    /// Refuse an offered file.
+    DeclineFile {
+        transfer_id: u64,
+        reply: oneshot::Sender<Result<()>>,
+    },
+    /// List every client registered on the hub.
+    ListPeers {
+        reply: oneshot::Sender<Result<Vec<DirectoryEntry>>>,
+    },
+    /// Ask the hub for clients whose username or PeerId is `query`.
+    LookupPeer {
+        query: String,
+        reply: oneshot::Sender<Result<Vec<DirectoryEntry>>>,
+    },
+    /// Look up the peer advertising username `name`.
+    Resolve {
+        name: String,
+        reply: oneshot::Sender<Option<PeerId>>,
+    },
+    /// How each connected peer is reached, hubs aside.
+    Peers {
+        reply: oneshot::Sender<Vec<PeerConnection>>,
+    },
+    Status {
+        reply: oneshot::Sender<Status>,
+    },
+    Subscribe {
+        reply: oneshot::Sender<mpsc::UnboundedReceiver<NodeEvent>>,
+    },
+    Shutdown {
+        reply: oneshot::Sender<()>,
+    },
+}
+
+/// Progress of an ongoing reservation, `reply` is unset for failovers
+struct PendingRelay {
+    addr: Multiaddr,
+    learned_observed_addr: bool,
+    told_relay_observed_addr: bool,
+    listener: Option<ListenerId>,
+    reply: Option<oneshot::Sender<Result<()>>>,
+}
+
+/// Relay holding our reservation
+struct Relay {
+    addr: Multiaddr,
+    listener: ListenerId,
+}
+
+/// Delay before the first reconnect, doubled on every failed round
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+pub(crate) struct EventLoop {
+    swarm: Swarm<Behaviour>,
+    commands: mpsc::Receiver<Command>,
+    subscribers: Subscribers,
+    directory: PeerDirectory,
+    username: Option<String>,
+    /// Hubs we may reserve on, configured ones first
+    hubs: Vec<(PeerId, Multiaddr)>,
+    /// How many reservations failover keeps up
+    reservations: usize,
+    relays: HashMap<PeerId, Relay>,
+    /// Hubs failover skips until a reservation succeeds again
+    failed: HashSet<PeerId>,
+    pending_relay: HashMap<PeerId, PendingRelay>,
+    /// Next attempt to get back to `reservations`, armed when no hub is left to fail over to
+    retry: Option<Fuse<Delay>>,
+    backoff: Duration,
+    pending_dial: HashMap<PeerId, oneshot::Sender<Result<()>>>,
+    pending_listen: HashMap<ListenerId, oneshot::Sender<Result<()>>>,
+    pending_directory: HashMap<RequestId, oneshot::Sender<Result<Vec<DirectoryEntry>>>>,
+    pending_chat: HashMap<RequestId, oneshot::Sender<Result<()>>>,
+    transfers: Transfers,
+    /// Prometheus metrics, when exported
+    metrics: Option<Metrics>,
+}
+
+impl EventLoop {
+    pub(crate) fn new(
+        swarm: Swarm<Behaviour>,
+        commands: mpsc::Receiver<Command>,
+        username: Option<String>,
+        transfers: Transfers,
+        hubs: Vec<(PeerId, Multiaddr)>,
+        reservations: usize,
+        metrics: Option<Metrics>,
+    ) -> Self {
+        Self {
+            swarm,
+            commands,
+            subscribers: Subscribers::default(),
+            directory: PeerDirectory::default(),
+            username,
+            hubs,
+            reservations,
+            relays: HashMap::new(),
+            failed: HashSet::new(),
+            pending_relay: HashMap::new(),
+            retry: None,
+            backoff: INITIAL_BACKOFF,
+            pending_dial: HashMap::new(),
+            pending_listen: HashMap::new(),
+            pending_directory: HashMap::new(),
+            pending_chat: HashMap::new(),
+            transfers,
+            metrics,
+        }
+    }
+
+    pub(crate) async fn run(mut self) {
+        loop { select! {
+            _ = OptionFuture::from(self.retry.as_mut()) => {
+                self.retry = None;
+                self.failed.clear();
+                self.failover();
+            },
+            event = self.swarm.select_next_some() => self.handle_swarm_event(event),
+            completion = self.transfers.completions.select_next_some() => {
+                let files = &mut self.swarm.behaviour_mut().files;
+                self.transfers.complete(files, &mut self.subscribers, completion);
+            },
+            command = self.commands.next() => match command {
+                Some(Command::Shutdown { reply }) => {
+                    info!("Shutting down client");
+                    let _ = reply.send(());
+                    break;
+                }
+                Some(command) => self.handle_command(command),
+                // All handles dropped, nobody can talk to us anymore
+                None => break,
+            },
+        } }
+    }
+
+    fn handle_command(&mut self, command: Command) {
+        match command {
+            Command::Listen { addr, reply } => {
+                match self.swarm.listen_on(addr) {
+                    Ok(listener_id) => {
+                        self.pending_listen.insert(listener_id, reply);
+                    }
+                    Err(err) => {
+                        let _ = reply.send(Err(err.into()));
+                    }
+                }
+            }
+            Command::Relay { relay_id, addr, reply } => {
+                self.reserve(relay_id, addr, Some(reply));
+            }
+            Command::Dial { peer_id, reply } => {
+                let addrs = self.circuit_addrs(peer_id);
+                if addrs.is_empty() {
+                    let _ = reply.send(Err(Error::NoRelay));
+                    return;
+                }
+                let entry = match self.pending_dial.entry(peer_id) {
+                    Entry::Occupied(_) => {
+                        let _ = reply.send(Err(Error::Protocol(
+                            format!("Already dialing {peer_id:?}")
+                        )));
+                        return;
+                    }
+                    Entry::Vacant(entry) => entry,
+                };
+                info!("Ready to dial peer {:?} through {} relays", peer_id, addrs.len());
+                let opts = DialOpts::peer_id(peer_id)
+                    .addresses(addrs)
+                    .condition(PeerCondition::Always)
+                    .build();
+                match self.swarm.dial(opts) {
+                    Ok(()) => {
+                        entry.insert(reply);
+                    }
+                    Err(err) => {
+                        let _ = reply.send(Err(err.into()));
+                    }
+                }
+            }
+            Command::SendMessage { peer_id, text, reply } => {
+                if let Err(err) = self.ensure_address(peer_id) {
+                    let _ = reply.send(Err(err));
+                    return;
+                }
+                let request_id = self.swarm.behaviour_mut().chat.send_request(&peer_id, ChatRequest { text });
+                self.pending_chat.insert(request_id, reply);
+            }
+            Command::SendFile { peer_id, path, sha256, size, reply } => {
+                if let Err(err) = self.ensure_address(peer_id) {
+                    let _ = reply.send(Err(err));
+                    return;
+                }
+                let files = &mut self.swarm.behaviour_mut().files;
+                self.transfers.offer(files, peer_id, path, sha256, size, reply);
+            }
+            Command::AcceptFile { transfer_id, reply } => {
+                let _ = reply.send(self.transfers.accept(transfer_id).map(|_| ()));
+            }
+            Command::DeclineFile { transfer_id, reply } => {
+                let files = &mut self.swarm.behaviour_mut().files;
+                let _ = reply.send(self.transfers.decline(files, transfer_id));
+            }
+            Command::ListPeers { reply } => {
+                self.query_directory(DirectoryRequest::List, reply);
+            }
+            Command::LookupPeer { query, reply } => {
+                self.query_directory(DirectoryRequest::Lookup { query }, reply);
+            }
+            Command::Resolve { name, reply } => {
+                let _ = reply.send(self.directory.lookup(&name));
+            }
+            Command::Peers { reply } => {
+                let peers = self.swarm.behaviour().connections.states()
+                    .filter(|(peer_id, _)| !self.is_hub(peer_id))
+                    .map(|(peer_id, state)| PeerConnection {
+                        peer_id,
+                        username: self.directory.username(&peer_id).map(str::to_string),
+                        state,
+                    })
+                    .collect();
+                let _ = reply.send(peers);
+            }
+            Command::Status { reply } => {
+                let _ = reply.send(Status::of(&self.swarm));
+            }
+            Command::Subscribe { reply } => {
+                let _ = reply.send(self.subscribers.subscribe());
+            }
+            Command::Shutdown { .. } => unreachable!("handled by the run loop"),
+        }
+    }
+
+    /// Starts reserving a slot on `relay_id`, it becomes a hub to fail over to once accepted.
+    fn reserve(&mut self, relay_id: PeerId, addr: Multiaddr, reply: Option<oneshot::Sender<Result<()>>>) {
+        if self.relays.contains_key(&relay_id) {
+            if let Some(reply) = reply {
+                let _ = reply.send(Ok(()));
+            }
+            return;
+        }
+        // Dial relay not for the reservation or relayed connection, but to:
+        // (a) learn our local public address,
+        // (b) enable a freshly started relay to learn its public address.
+        // If reservation is requested when relay hasn't acknowledged
+        // its public address yet, the reservation will fail.
+        let pending = PendingRelay {
+            addr: addr.clone(),
+            learned_observed_addr: false,
+            told_relay_observed_addr: false,
+            listener: None,
+            reply,
+        };
+        if let Some(previous) = self.pending_relay.insert(relay_id, pending) {
+            if let Some(reply) = previous.reply {
+                let _ = reply.send(Err(Error::Reservation(
+                    "Superseded by a newer relay request".to_string()
+                )));
+            }
+        }
+        if let Err(err) = self.swarm.dial(addr) {
+            self.finish_relay(relay_id, Err(err.into()));
+        }
+    }
+
+    /// Moves an ongoing relay request forward once both sides learned
+    /// their public addresses through identify.
+    fn advance_relay(&mut self, relay_id: PeerId) {
+        let pending = match self.pending_relay.get_mut(&relay_id) {
+            Some(pending) => pending,
+            None => return,
+        };
+        if pending.listener.is_some() || !(pending.learned_observed_addr && pending.told_relay_observed_addr) {
+            return;
+        }
+
+        // listen from relay server
+        match self.swarm.listen_on(pending.addr.clone().with(Protocol::P2pCircuit)) {
+            Ok(listener) => pending.listener = Some(listener),
+            Err(err) => self.finish_relay(relay_id, Err(err.into())),
+        }
+    }
+
+    /// Settles the reservation pending on `relay_id`. Failed failovers
+    /// move on to the next hub, callers of `Command::Relay` get the error.
+    fn finish_relay(&mut self, relay_id: PeerId, result: Result<()>) {
+        let pending = match self.pending_relay.remove(&relay_id) {
+            Some(pending) => pending,
+            None => return,
+        };
+        match (&result, pending.listener) {
+            (Ok(()), Some(listener)) => {
+                self.failed.clear();
+                self.backoff = INITIAL_BACKOFF;
+                // Only now, a mistyped `relay <peer>` must not become a hub
+                if !self.is_hub(&relay_id) {
+                    self.hubs.push((relay_id, pending.addr.clone()));
+                }
+                self.relays.insert(relay_id, Relay { addr: pending.addr, listener });
+                // Hubs that failed meanwhile are worth another try now
+                self.failover();
+            }
+            (Ok(()), None) => {}
+            (Err(err), listener) => {
+                if let Some(listener) = listener {
+                    self.swarm.remove_listener(listener);
+                }
+                self.failed.insert(relay_id);
+                if pending.reply.is_none() {
+                    warn!("Failing over to {relay_id:?} failed: {err}");
+                    self.failover();
+                } else {
+                    self.schedule_retry();
+                }
+            }
+        }
+        if let Some(reply) = pending.reply {
+            let _ = reply.send(result);
+        }
+    }
+
+    /// Drops the reservation on `relay_id` and fails over to another hub.
+    fn lose_relay(&mut self, relay_id: PeerId, reason: &str) {
+        if let Some(relay) = self.relays.remove(&relay_id) {
+            warn!("Lost reservation on {relay_id:?}: {reason}");
+            self.swarm.remove_listener(relay.listener);
+            self.failed.insert(relay_id);
+            self.failover();
+        }
+    }
+
+    /// Reserves on hubs we aren't on yet, until we hold `reservations` of them.
+    fn failover(&mut self) {
+        let free = self.reservations.saturating_sub(self.relays.len() + self.pending_relay.len());
+        let targets = failover_targets(&self.hubs, free, |hub_id| {
+            self.relays.contains_key(hub_id)
+                || self.pending_relay.contains_key(hub_id)
+                || self.failed.contains(hub_id)
+        });
+        for (relay_id, addr) in targets {
+            // A dial failing right away fails over on its own and may have taken the slot
+            if self.relays.len() + self.pending_relay.len() >= self.reservations
+                || self.pending_relay.contains_key(&relay_id)
+            {
+                continue;
+            }
+            info!("Failing over to hub {relay_id:?}");
+            self.reserve(relay_id, addr, None);
+        }
+        if self.relays.len() + self.pending_relay.len() < self.reservations {
+            warn!("No hub left to fail over to");
+            self.schedule_retry();
+        }
+    }
+
+    /// Arms `retry` with exponential backoff and jitter, unless we hold
+    /// enough reservations or a retry is armed already.
+    fn schedule_retry(&mut self) {
+        if self.retry.is_some() || self.relays.len() + self.pending_relay.len() >= self.reservations {
+            return;
+        }
+        let delay = with_jitter(self.backoff);
+        info!("Reconnecting to hubs in {delay:?}");
+        self.retry = Some(Delay::new(delay).fuse());
+        self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+    }
+
+    fn is_hub(&self, peer_id: &PeerId) -> bool {
+        self.hubs.iter().any(|(hub_id, _)| hub_id == peer_id)
+    }
+
+    /// Hub answering directory queries, the first reserved one in config order.
+    fn primary_relay(&self) -> Option<PeerId> {
+        self.hubs.iter()
+            .map(|(hub_id, _)| *hub_id)
+            .find(|hub_id| self.relays.contains_key(hub_id))
+    }
+
+    fn query_directory(
+        &mut self,
+        request: DirectoryRequest,
+        reply: oneshot::Sender<Result<Vec<DirectoryEntry>>>,
+    ) {
+        match self.primary_relay() {
+            Some(relay_id) => {
+                let request_id = self.swarm.behaviour_mut().directory.send_request(&relay_id, request);
+                self.pending_directory.insert(request_id, reply);
+            }
+            None => {
+                let _ = reply.send(Err(Error::NoRelay));
+            }
+        }
+    }
+
+    /// Makes sure request-response protocols can reach `peer_id`, dialing
+    /// through the relays when there's no connection yet.
+    fn ensure_address(&mut self, peer_id: PeerId) -> Result<()> {
+        if self.swarm.is_connected(&peer_id) {
+            return Ok(());
+        }
+        let addrs = self.circuit_addrs(peer_id);
+        if addrs.is_empty() {
+            return Err(Error::NoRelay);
+        }
+        let behaviour = self.swarm.behaviour_mut();
+        for addr in addrs {
+            behaviour.chat.add_address(&peer_id, addr.clone());
+            behaviour.files.add_address(&peer_id, addr);
+        }
+        Ok(())
+    }
+
+    /// Addresses reaching `peer_id` through each reserved relay, in config order.
+    fn circuit_addrs(&self, peer_id: PeerId) -> Vec<Multiaddr> {
+        self.hubs.iter()
+            .filter_map(|(hub_id, _)| self.relays.get(hub_id))
+            .map(|relay| circuit_addr(&relay.addr, peer_id))
+            .collect()
+    }
+
+    /// Publishes our username and circuit address on a relay we reserved.
+    fn register(&mut self, relay_peer_id: PeerId) {
+        let circuit_addr = match self.relays.get(&relay_peer_id) {
+            Some(relay) => circuit_addr(&relay.addr, *self.swarm.local_peer_id()),
+            None => return,
+        };
+        let request = DirectoryRequest::Register {
+            username: self.username.clone(),
+            circuit_addr: circuit_addr.to_string(),
+        };
+        self.swarm.behaviour_mut().directory.send_request(&relay_peer_id, request);
+    }
+
+    fn handle_directory_response(&mut self, request_id: RequestId, response: DirectoryResponse) {
+        let result = match response {
+            DirectoryResponse::Registered => {
+                info!("Registered on the hub directory");
+                return;
+            }
+            DirectoryResponse::Entries(entries) => {
+                let entries: Vec<DirectoryEntry> = entries.into_iter()
+                    .filter_map(|entry| match DirectoryEntry::try_from(entry) {
+                        Ok(entry) => Some(entry),
+                        Err(err) => {
+                            warn!("Ignoring malformed directory entry: {err}");
+                            None
+                        }
+                    })
+                    .collect();
+                for entry in &entries {
+                    if let Some(username) = &entry.username {
+                        self.directory.insert(entry.peer_id, username.clone());
+                    }
+                }
+                Ok(entries)
+            }
+            DirectoryResponse::Error(err) => {
+                warn!("Hub directory refused: {err}");
+                Err(Error::Request(err))
+            }
+        };
+        if let Some(reply) = self.pending_directory.remove(&request_id) {
+            let _ = reply.send(result);
+        }
+    }
+
+    fn handle_swarm_event<E: Debug>(&mut self, event: SwarmEvent<crate::Event, E>) {
+        if let Some(metrics) = &self.metrics {
+            metrics::record(metrics, &event);
+        }
+        if let Some(node_event) = NodeEvent::from_swarm_event(&event) {
+            self.subscribers.publish(node_event);
+        }
+        match event {
+            SwarmEvent::Behaviour(IdentifyEvent(event)) => {
+                info!("Identify {event:?}");
+                match event {
+                    IdentifyEventKinds::Sent { peer_id } => {
+                        if let Some(pending) = self.pending_relay.get_mut(&peer_id) {
+                            info!("Told relay its public address.");
+                            pending.told_relay_observed_addr = true;
+                        }
+                        self.advance_relay(peer_id);
+                    }
+                    IdentifyEventKinds::Received {
+                        peer_id,
+                        info: IdentifyInfo { observed_addr, agent_version, .. },
+                    } => {
+                        if let Some(username) = username_from_agent_version(&agent_version) {
+                            if self.directory.claim(peer_id, username.to_string()) {
+                                info!("Peer {peer_id:?} is known as {username}");
+                            } else {
+                                warn!("Peer {peer_id:?} claims {username}, already held by another peer");
+                            }
+                        }
+                        if let Some(pending) = self.pending_relay.get_mut(&peer_id) {
+                            info!("Relay told us our public address: {:?}", observed_addr);
+                            pending.learned_observed_addr = true;
+                        }
+                        self.advance_relay(peer_id);
+                    }
+                    _ => {}
+                }
+            }
+            SwarmEvent::Behaviour(PingEvent(event)) => {
+                info!("Ping {event:?}")
+            }
+            SwarmEvent::Behaviour(DcutrEvent(event)) => {
+                info!("Dcutr {event:?}");
+                let connections = &mut self.swarm.behaviour_mut().connections;
+                match event {
+                    DcutrEventKinds::InitiatedDirectConnectionUpgrade { remote_peer_id, .. }
+                    | DcutrEventKinds::RemoteInitiatedDirectConnectionUpgrade { remote_peer_id, .. } => {
+                        connections.upgrade_started(remote_peer_id);
+                    }
+                    DcutrEventKinds::DirectConnectionUpgradeSucceeded { remote_peer_id } => {
+                        connections.upgrade_succeeded(remote_peer_id);
+                    }
+                    DcutrEventKinds::DirectConnectionUpgradeFailed { remote_peer_id, error } => {
+                        warn!("Hole punching with {remote_peer_id:?} failed, staying relayed: {error:?}");
+                        connections.upgrade_failed(remote_peer_id, format!("{error:?}"));
+                    }
+                }
+            }
+            SwarmEvent::Behaviour(RelayClientEvent(event)) => {
+                info!("Relay {event:?}");
+                match event {
+                    RelayClientEventKinds::ReservationReqAccepted { relay_peer_id, renewal, .. } => {
+                        self.finish_relay(relay_peer_id, Ok(()));
+                        if !renewal {
+                            self.register(relay_peer_id);
+                        }
+                    }
+                    RelayClientEventKinds::ReservationReqFailed { relay_peer_id, renewal: true, error } => {
+                        self.lose_relay(relay_peer_id, &format!("renewal refused: {error:?}"));
+                    }
+                    RelayClientEventKinds::ReservationReqFailed { relay_peer_id, error, .. } => {
+                        self.finish_relay(relay_peer_id, Err(Error::Reservation(
+                            format!("{relay_peer_id:?} refused: {error:?}")
+                        )));
+                    }
+                    _ => {}
+                }
+            }
+            SwarmEvent::Behaviour(DirectoryEvent(RequestResponseEvent::Message {
+                message: RequestResponseMessage::Response { request_id, response },
+                ..
+            })) => {
+                self.handle_directory_response(request_id, response);
+            }
+            SwarmEvent::Behaviour(DirectoryEvent(RequestResponseEvent::OutboundFailure {
+                peer, request_id, error
+            })) => {
+                warn!("Directory request to {peer:?} failed: {error:?}");
+                if let Some(reply) = self.pending_directory.remove(&request_id) {
+                    let _ = reply.send(Err(Error::Request(format!("{error:?}"))));
+                }
+            }
+            SwarmEvent::Behaviour(ChatEvent(RequestResponseEvent::Message {
+                peer,
+                message: RequestResponseMessage::Request { request, channel, .. },
+            })) => {
+                let name = self.directory.username(&peer).unwrap_or("unknown");
+                info!("Message from {peer:?}({name}): {}", request.text);
+                if self.swarm.behaviour_mut().chat.send_response(channel, ChatResponse).is_err() {
+                    warn!("Message ack to {peer:?} dropped, connection closed");
+                }
+            }
+            SwarmEvent::Behaviour(ChatEvent(RequestResponseEvent::Message {
+                message: RequestResponseMessage::Response { request_id, .. },
+                ..
+            })) => {
+                if let Some(reply) = self.pending_chat.remove(&request_id) {
+                    let _ = reply.send(Ok(()));
+                }
+            }
+            SwarmEvent::Behaviour(ChatEvent(RequestResponseEvent::OutboundFailure {
+                peer, request_id, error
+            })) => {
+                warn!("Message to {peer:?} failed: {error:?}");
+                if let Some(reply) = self.pending_chat.remove(&request_id) {
+                    let _ = reply.send(Err(Error::Request(format!("{error:?}"))));
+                }
+            }
+            SwarmEvent::Behaviour(FileEvent(RequestResponseEvent::Message { peer, message })) => {
+                let files = &mut self.swarm.behaviour_mut().files;
+                match message {
+                    RequestResponseMessage::Request { request, channel, .. } => {
+                        self.transfers.handle_request(files, &mut self.subscribers, peer, request, channel);
+                    }
+                    RequestResponseMessage::Response { request_id, response } => {
+                        self.transfers.handle_response(files, &mut self.subscribers, request_id, response);
+                    }
+                }
+            }
+            SwarmEvent::Behaviour(FileEvent(RequestResponseEvent::OutboundFailure {
+                peer, request_id, error
+            })) => {
+                warn!("File request to {peer:?} failed: {error:?}");
+                self.transfers.handle_failure(&mut self.subscribers, request_id, format!("{error:?}"));
+            }
+            SwarmEvent::Behaviour(FileEvent(event)) => {
+                debug!("File {event:?}")
+            }
+            SwarmEvent::Behaviour(e) => {
+                info!("Event {:?}", e)
+            },
+            SwarmEvent::ConnectionEstablished {
+                peer_id, endpoint, num_established: _, concurrent_dial_errors: _
+            } => {
+                info!("Established connection to {peer_id:?} via {endpoint:?}");
+                if let Some(reply) = self.pending_dial.remove(&peer_id) {
+                    let _ = reply.send(Ok(()));
+                }
+            },
+            SwarmEvent::ConnectionClosed {
+                peer_id, endpoint, num_established, cause
+            } => {
+                let name = self.directory.username(&peer_id).unwrap_or("unknown");
+                error!("Connection with {peer_id:?}({name})@{endpoint:?} closed due to {cause:?}");
+                if num_established == 0 {
+                    self.lose_relay(peer_id, "connection closed");
+                    self.finish_relay(peer_id, Err(Error::Reservation(
+                        format!("Connection to {peer_id:?} closed")
+                    )));
+                }
+            },
+            SwarmEvent::IncomingConnection {
+                local_addr: _, send_back_addr
+            } => {
+                debug!("Incoming connection from {send_back_addr}");
+            },
+            SwarmEvent::IncomingConnectionError {
+                local_addr: _, send_back_addr, error
+            } => {
+                error!("Incoming connection from {send_back_addr} error: {error}");
+            },
+            SwarmEvent::OutgoingConnectionError {
+                peer_id, error
+            } => {
+                error!("Outgoing connection error to {:?}: {:?}", peer_id, error);
+                if let Some(peer_id) = peer_id {
+                    if let Some(reply) = self.pending_dial.remove(&peer_id) {
+                        let _ = reply.send(Err(error.into()));
+                    } else {
+                        self.finish_relay(peer_id, Err(error.into()));
+                    }
+                }
+            }
+            SwarmEvent::BannedPeer {
+                peer_id, endpoint
+            } => {
+                warn!("Refused banned peer {peer_id:?}@{endpoint:?}");
+            },
+            SwarmEvent::NewListenAddr {
+                listener_id, address
+            } => {
+                info!("Listening on {listener_id:?}@{address}");
+                if let Some(reply) = self.pending_listen.remove(&listener_id) {
+                    let _ = reply.send(Ok(()));
+                }
+            },
+            SwarmEvent::ExpiredListenAddr {
+                listener_id, address
+            } => {
+                info!("Stopped listening to {listener_id:?}@{address}");
+            },
+            SwarmEvent::ListenerClosed {
+                listener_id, addresses: _, reason
+            } => {
+                error!("Listener {listener_id:?} closed due to {reason:?}");
+                let relay_id = self.relays.iter()
+                    .find(|(_, relay)| relay.listener == listener_id)
+                    .map(|(relay_id, _)| *relay_id);
+                if let Some(relay_id) = relay_id {
+                    self.lose_relay(relay_id, "circuit listener closed");
+                }
+                let pending_id = self.pending_relay.iter()
+                    .find(|(_, pending)| pending.listener == Some(listener_id))
+                    .map(|(relay_id, _)| *relay_id);
+                if let Some(relay_id) = pending_id {
+                    self.finish_relay(relay_id, Err(Error::Reservation("Circuit listener closed".to_string())));
+                }
+                if let Some(reply) = self.pending_listen.remove(&listener_id) {
+                    let err = match reason {
+                        Err(error) => TransportError::Other(error).into(),
+                        Ok(()) => Error::Protocol("Listener closed before listening".to_string()),
+                    };
+                    let _ = reply.send(Err(err));
+                }
+            },
+            SwarmEvent::ListenerError {
+                listener_id, error
+            } => {
+                error!("Listener {listener_id:?} error: {error}");
+                if let Some(reply) = self.pending_listen.remove(&listener_id) {
+                    let _ = reply.send(Err(TransportError::Other(error).into()));
+                }
+            },
+            SwarmEvent::Dialing(peer_id) => {
+                info!("Dailing {peer_id:?}");
+            }
+        }
+    }
+}
+
+/// Address reaching `peer_id` through the relay at `relay_addr`.
+fn circuit_addr(relay_addr: &Multiaddr, peer_id: PeerId) -> Multiaddr {
+    relay_addr.clone()
+        .with(Protocol::P2pCircuit)
+        .with(Protocol::P2p(peer_id.into()))
+}
+
+/// Up to `free` hubs to reserve on, in config order, skipping `busy` ones.
+fn failover_targets(
+    hubs: &[(PeerId, Multiaddr)],
+    free: usize,
+    busy: impl Fn(&PeerId) -> bool,
+) -> Vec<(PeerId, Multiaddr)> {
+    hubs.iter().filter(|(hub_id, _)| !busy(hub_id)).take(free).cloned().collect()
+}
+
+/// `backoff` plus up to half of it again, so clients of a restarted hub
+/// don't all come back at the same moment.
+fn with_jitter(backoff: Duration) -> Duration {
+    let mut bytes = [0u8; 4];
+    if getrandom::getrandom(&mut bytes).is_err() {
+        return backoff;
+    }
+    let jitter = u32::from_le_bytes(bytes) as f64 / u32::MAX as f64;
+    backoff + backoff.mul_f64(jitter / 2.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::failover_targets;
+    use libp2p::{Multiaddr, PeerId};
+    use std::collections::HashSet;
+
+    #[test]
+    fn retry_refills_every_slot() {
+        let hubs: Vec<(PeerId, Multiaddr)> = (1..=3)
+            .map(|n| (PeerId::random(), format!("/ip4/10.0.0.{n}/tcp/8443").parse().unwrap()))
+            .collect();
+        let hub_ids: Vec<PeerId> = hubs.iter().map(|(hub_id, _)| *hub_id).collect();
+
+        // Both reserved hubs went down at once, only the third is left to try
+        let mut failed: HashSet<PeerId> = hub_ids[..2].iter().copied().collect();
+        let targets = failover_targets(&hubs, 2, |hub_id| failed.contains(hub_id));
+        assert_eq!(targets, [hubs[2].clone()]);
+
+        // The third is down too, nothing left until the retry fires
+        failed.insert(hub_ids[2]);
+        assert!(failover_targets(&hubs, 2, |hub_id| failed.contains(hub_id)).is_empty());
+
+        // Retry forgets the failures and refills both slots in config order
+        failed.clear();
+        let targets = failover_targets(&hubs, 2, |hub_id| failed.contains(hub_id));
+        assert_eq!(targets, hubs[..2]);
+    }
+}
@@ -1,55 +1,75 @@
 /// Defines Client
 
+use libp2p::core::either::EitherTransport;
 use libp2p::core::upgrade;
+use libp2p::core::transport::TransportError;
 use libp2p::core::transport::OrTransport;
 use libp2p::tcp::{GenTcpConfig, TcpTransport};
 use libp2p::dns::DnsConfig;
 use libp2p::Transport;
-use libp2p::multiaddr::Protocol;
 use libp2p::noise::NoiseConfig;
+use libp2p::websocket::WsConfig;
+use libp2p::pnet::PnetConfig;
 use libp2p::PeerId;
-use libp2p::swarm::{Swarm, SwarmBuilder, SwarmEvent};
-// use libp2p::relay::v2::client::{Event as RelayClientEventKinds, Client as RelayClient};
+use libp2p::swarm::SwarmBuilder;
 use libp2p::relay::v2::client::Client as RelayClient;
-use libp2p::identify::{IdentifyEvent as IdentifyEventKinds, IdentifyInfo};
+use futures::channel::{mpsc, oneshot};
 use futures::executor::block_on;
-use futures::future::FutureExt;
-use futures::stream::StreamExt;
-use std::time::Duration;
+use futures::sink::SinkExt;
+use std::path::Path;
 use std::str::FromStr;
-use log::{info, error, debug};
-use async_std::sync::{Arc, Mutex, RwLock};
-use futures::select;
+use log::warn;
+use async_std::sync::Mutex;
+use async_std::task::{self, JoinHandle};
 
 pub mod behaviour;
+mod connections;
+mod event_loop;
 
 use super::conf;
 use super::keys::Keys;
+use crate::command::{entry_lines, lines, Command as PromptCommand, CommandError, Outcome};
+use crate::error::{Error, Result};
+use crate::metrics::{self, Exporter};
+use crate::event::{NodeEvent, Status};
+use crate::directory::DirectoryEntry;
+use crate::transfer::{hash_file, Transfers};
 use behaviour::Behaviour;
-use crate::Event::RelayClient as RelayClientEvent;
-use crate::Event::Identify as IdentifyEvent;
-use crate::Event::Ping as PingEvent;
-use crate::Event::Dcutr as DcutrEvent;
+use event_loop::{Command, EventLoop};
+
+pub use connections::{ConnectionState, PeerConnection};
 
 pub struct Client {
     pub keys: Keys,
-    pub swarm: Arc<Mutex<Swarm<Behaviour>>>,
     conf: conf::Conf,
-    relay_id: RwLock<Option<PeerId>>,
+    commands: mpsc::Sender<Command>,
+    task: Mutex<Option<JoinHandle<()>>>,
 }
 
 impl Client {
-    pub fn new(conf: conf::Conf) -> Self {
-        let local_keys = Keys::new();
+    pub fn new(conf: conf::Conf) -> Result<Self> {
+        let local_keys = match conf.get_key_path() {
+            Some(path) => Keys::load_or_generate(path).map_err(Error::Key)?,
+            None => Keys::new(),
+        };
         let local_public_key = local_keys.key.public();
 
+        let dns_tcp = || block_on(DnsConfig::system(TcpTransport::new(
+            GenTcpConfig::default().port_reuse(true),
+        ))).map_err(TransportError::Other);
+        // WebSocket outermost so `/dns/.../wss` keeps the host name for TLS
+        let tcp_transport = OrTransport::new(WsConfig::new(dns_tcp()?), dns_tcp()?);
+        // Circuits ride on our connection to the hub, so they're covered by its pnet layer
+        let tcp_transport = match conf.get_psk()? {
+            Some(psk) => EitherTransport::Left(
+                tcp_transport.and_then(move |socket, _| PnetConfig::new(psk).handshake(socket))
+            ),
+            None => EitherTransport::Right(tcp_transport),
+        };
         let (relay_transport, client) = RelayClient::new_transport_and_behaviour(local_keys.peer_id);
         let transport = OrTransport::new(
             relay_transport,
-            block_on(DnsConfig::system(TcpTransport::new(
-                GenTcpConfig::default().port_reuse(true),
-            )))
-            .unwrap()
+            tcp_transport,
         )
         .upgrade(upgrade::Version::V1)
         .authenticate(NoiseConfig::xx(local_keys.noise_key.clone()).into_authenticated())
@@ -58,234 +78,212 @@ impl Client {
 
         let swarm = SwarmBuilder::new(
             transport,
-            Behaviour::new(local_public_key, client),
+            Behaviour::new(local_public_key, client, conf.get_username(), conf.get_close_relayed()),
             local_keys.peer_id,
         )
         .dial_concurrency_factor(10_u8.try_into().unwrap())
         .build();
 
-        Self {
+        let (exporter, metrics) = match conf.get_metrics_addr()? {
+            Some(addr) => Exporter::bind(addr).map(|(exporter, metrics)| (Some(exporter), Some(metrics)))?,
+            None => (None, None),
+        };
+        let (commands, receiver) = mpsc::channel(16);
+        let username = conf.get_username().map(str::to_string);
+        let transfers = Transfers::new(conf.get_download_dir());
+        let event_loop = EventLoop::new(
+            swarm, receiver, username, transfers, conf.get_hubs(), conf.get_hub_reservations(), metrics,
+        );
+        let task = task::spawn(metrics::run(exporter, event_loop.run()));
+
+        Ok(Self {
             keys: local_keys,
-            swarm: Arc::new(Mutex::new(swarm)),
             conf: conf,
-            relay_id: RwLock::new(None),
-        }
+            commands: commands,
+            task: Mutex::new(Some(task)),
+        })
     }
 
     pub fn set_peer_id(&mut self) {
         self.keys.peer_id = PeerId::random();
     }
 
-    pub async fn bind(&self) {
-        let listen_addr = self.conf.get_bind_address();
+    pub async fn bind(&self) -> Result<()> {
+        for addr in self.conf.get_bind_addresses() {
+            self.call(|reply| Command::Listen { addr, reply }).await??;
+        }
+        Ok(())
+    }
 
-        let mut guard = self.swarm.lock_arc().await;
-        guard.listen_on(listen_addr).unwrap();
-        
-        let mut delay = futures_timer::Delay::new(Duration::from_secs(1)).fuse();
-        loop { select! {
-            event = guard.next() => {
-                match event.unwrap() {
-                    SwarmEvent::NewListenAddr { address, .. } => {
-                        info!("Listening on {:?}", address);
-                    }
-                    event => panic!("{:?}", event),
+    /// Runs a prompt command, `Help` is answered by `CommandRegistry`.
+    pub async fn run(&self, command: PromptCommand) -> Result<Outcome, CommandError> {
+        match command {
+            PromptCommand::Relay { relay_id: Some(relay_id) } => self.relay(relay_id).await?,
+            PromptCommand::Relay { relay_id: None } => {
+                if self.connect_hub().await?.is_empty() {
+                    return Err(Error::Config("No hub configured, pass a PeerId".to_string()).into());
                 }
             }
-            _ = delay => {
-                // Likely listening on all interfaces now, thus continuing by breaking the loop.
-                break;
+            PromptCommand::Dial { peer } => {
+                let peer_id = self.resolve(&peer).await?;
+                self.relay_peer(peer_id).await?;
+            }
+            PromptCommand::Send { peer, text } => {
+                let peer_id = self.resolve(&peer).await?;
+                self.send_message(peer_id, &text).await?;
+            }
+            PromptCommand::SendFile { peer, path } => {
+                let peer_id = self.resolve(&peer).await?;
+                let transfer_id = self.send_file(peer_id, &path).await?;
+                let offered = format!("Offered {} as transfer {transfer_id}", path.display());
+                return Ok(Outcome::Output(offered));
             }
-        } }
+            PromptCommand::Accept { transfer_id } => self.accept_file(transfer_id).await?,
+            PromptCommand::Decline { transfer_id } => self.decline_file(transfer_id).await?,
+            PromptCommand::List => return Ok(entry_lines(self.list_peers().await?)),
+            PromptCommand::Lookup { query } => {
+                return Ok(entry_lines(self.lookup_peer(&query).await?));
+            }
+            PromptCommand::Peers => {
+                return Ok(lines(self.peers().await?, "No peer connected", |peer| {
+                    let name = peer.username.as_deref().unwrap_or("-");
+                    format!("{} {name} {}", peer.peer_id, peer.state)
+                }));
+            }
+            PromptCommand::Quit => return Ok(Outcome::Quit),
+            // Hub commands and `Help`
+            command => {
+                return Err(CommandError::Unknown(format!("{command:?}")));
+            }
+        }
+        Ok(Outcome::Continue)
     }
 
-    pub async fn execute(&self, user_input: String) -> Result<bool, String> {
-        let mut iter = user_input.split_whitespace();
-        match iter.next() {
-            Some("relay") | Some("r") => {
-                if let Some(peer_id) = iter.next() {
-                    match PeerId::from_str(peer_id) {
-                        Ok(peer_id) => {
-                            self.relay(peer_id).await;
-                            Ok(false)
-                        },
-                        Err(err) => {
-                            Err(err.to_string() + " - PeerId invalid!")
-                        },
-                    }
-                }
-                else {
-                    Err("Please input peerid as the second param.".to_string())
-                }
-            },
-            Some("dial") | Some("d") => {
-                if let Some(peer_id) = iter.next() {
-                    match PeerId::from_str(peer_id) {
-                        Ok(peer_id) => {
-                            self.relay_peer(peer_id).await;
-                            Ok(false)
-                        },
-                        Err(err) => {
-                            Err(err.to_string() + " - PeerId invalid!")
-                        }
-                    }
-                }
-                else {
-                    Err("Please input peerid as the second param.".to_string())
+    /// Reserves a slot on the relay and listens for peers through it.
+    pub async fn relay(&self, relay_id: PeerId) -> Result<()> {
+        let addr = self.conf.get_relay_address(relay_id)
+            .ok_or_else(|| Error::Config("Relay address is only known to clients".to_string()))?;
+        self.call(|reply| Command::Relay { relay_id, addr, reply }).await?
+    }
+
+    /// Reserves on the configured hubs in order, skipping those failing,
+    /// until `hub_reservations` of them accepted. Returns the hubs reserved.
+    pub async fn connect_hub(&self) -> Result<Vec<PeerId>> {
+        let mut reserved = Vec::new();
+        let mut last_error = None;
+        for (relay_id, addr) in self.conf.get_hubs() {
+            if reserved.len() >= self.conf.get_hub_reservations() {
+                break;
+            }
+            match self.call(|reply| Command::Relay { relay_id, addr, reply }).await? {
+                Ok(()) => reserved.push(relay_id),
+                Err(err) => {
+                    warn!("Reserving on hub {relay_id:?} failed: {err}");
+                    last_error = Some(err);
                 }
-            },
-            Some("quit") | Some("q") => Ok(true),
-            None => Ok(false),
-            _ => {
-                Err("Command invalid!".to_string())
             }
         }
+        match last_error {
+            Some(err) if reserved.is_empty() => Err(err),
+            _ => Ok(reserved),
+        }
     }
 
-    pub async fn relay(&self, relay_id: PeerId) {
-        let mut writer = self.relay_id.write().await;
-        *writer = Some(relay_id);
-        drop(writer);
-        let addr = self.conf.get_relay_address(relay_id).unwrap();
-        // Dial relay not for the reservation or relayed connection, but to:
-        // (a) learn our local public address,
-        // (b) enable a freshly started relay to learn its public address.
-        // If reservation is requested when relay hasn't acknowledged
-        // its public address yet, the reservation will fail.
-        info!("Getting swarm dial lock");
-        let mut guard = self.swarm.lock_arc().await;
-        info!("swarm dial lock success");
-        guard.dial(addr.clone()).unwrap();
-        let mut learned_observed_addr = false;
-        let mut told_relay_observed_addr = false;
+    /// Dials a peer through the reserved relay, resolves once connected.
+    pub async fn relay_peer(&self, peer_id: PeerId) -> Result<()> {
+        self.call(|reply| Command::Dial { peer_id, reply }).await?
+    }
 
-        loop {
-            match guard.next().await.unwrap() {
-                SwarmEvent::NewListenAddr { .. } => {}
-                SwarmEvent::Dialing { .. } => {}
-                SwarmEvent::ConnectionEstablished { .. } => {}
-                SwarmEvent::Behaviour(PingEvent(_)) => {}
-                SwarmEvent::Behaviour(IdentifyEvent(IdentifyEventKinds::Sent { .. })) => {
-                    info!("Told relay its public address.");
-                    told_relay_observed_addr = true;
-                }
-                SwarmEvent::Behaviour(IdentifyEvent(IdentifyEventKinds::Received {
-                    info: IdentifyInfo { observed_addr, .. },
-                    ..
-                })) => {
-                    info!("Relay told us our public address: {:?}", observed_addr);
-                    learned_observed_addr = true;
-                }
-                SwarmEvent::OutgoingConnectionError {
-                    peer_id, error
-                } => {
-                    error!("Outgoing connection error to {:?}: {:?}", peer_id, error);
-                }
-                event => panic!("{:?}", event),
-            }
+    /// Connected peers, hubs aside, and whether hole punching got us
+    /// a direct connection to them.
+    pub async fn peers(&self) -> Result<Vec<PeerConnection>> {
+        self.call(|reply| Command::Peers { reply }).await
+    }
 
-            if learned_observed_addr && told_relay_observed_addr {
-                break;
-            }
-        }
+    /// Our listen addresses and connected peers.
+    pub async fn status(&self) -> Result<Status> {
+        self.call(|reply| Command::Status { reply }).await
+    }
+
+    /// Streams every `NodeEvent` happening from now on.
+    pub async fn subscribe(&self) -> Result<mpsc::UnboundedReceiver<NodeEvent>> {
+        self.call(|reply| Command::Subscribe { reply }).await
+    }
+
+    /// Sends a direct message, resolves once `peer_id` acknowledged it.
+    pub async fn send_message(&self, peer_id: PeerId, text: &str) -> Result<()> {
+        let text = text.to_string();
+        self.call(|reply| Command::SendMessage { peer_id, text, reply }).await?
+    }
+
+    /// Offers the file at `path` to `peer_id`, returns the transfer id once
+    /// the peer acknowledged the offer.
+    pub async fn send_file(&self, peer_id: PeerId, path: impl AsRef<Path>) -> Result<u64> {
+        let path = path.as_ref().to_path_buf();
+        let hashed = path.clone();
+        let (sha256, size) = task::spawn_blocking(move || hash_file(&hashed))
+            .await
+            .map_err(Error::Io)?;
+        self.call(|reply| Command::SendFile { peer_id, path, sha256, size, reply }).await?
+    }
 
-        // listen from relay server
-        guard.listen_on(addr.with(Protocol::P2pCircuit)).unwrap();
+    /// Starts receiving an offered file, or resumes an interrupted one.
+    pub async fn accept_file(&self, transfer_id: u64) -> Result<()> {
+        self.call(|reply| Command::AcceptFile { transfer_id, reply }).await?
     }
 
-    pub async fn relay_peer(&self, peer_id: PeerId) {
-        let reader = self.relay_id.read().await;
-        if let Some(relay_id) = *reader {
-            let addr = self.conf.get_relay_address(relay_id).unwrap();
-            let mut guard = self.swarm.lock_arc().await;
-            info!("Ready to dial peer {:?}", peer_id);
-            guard.dial(
-                addr.clone()
-                    .with(Protocol::P2pCircuit)
-                    .with(Protocol::P2p(peer_id.into()))
-            ).unwrap();
+    /// Refuses an offered file.
+    pub async fn decline_file(&self, transfer_id: u64) -> Result<()> {
+        self.call(|reply| Command::DeclineFile { transfer_id, reply }).await?
+    }
+
+    /// Lists the clients registered on the reserved hub.
+    pub async fn list_peers(&self) -> Result<Vec<DirectoryEntry>> {
+        self.call(|reply| Command::ListPeers { reply }).await?
+    }
+
+    /// Asks the reserved hub for clients named `query` or with PeerId `query`.
+    pub async fn lookup_peer(&self, query: &str) -> Result<Vec<DirectoryEntry>> {
+        let query = query.to_string();
+        self.call(|reply| Command::LookupPeer { query, reply }).await?
+    }
+
+    /// Turns a PeerId or a username into a PeerId. The hub directory, which keeps
+    /// names unique, is asked first; names identified peers advertised are only
+    /// a fallback, as anybody may claim any name through identify.
+    pub async fn resolve(&self, peer: &str) -> Result<PeerId> {
+        if let Ok(peer_id) = PeerId::from_str(peer) {
+            return Ok(peer_id);
         }
-        else {
-            error!("Relay not found, can't dial peer!");
+        match self.lookup_peer(peer).await {
+            Ok(entries) => {
+                if let Some(entry) = entries.iter().find(|entry| entry.matches(peer)) {
+                    return Ok(entry.peer_id);
+                }
+            }
+            Err(Error::NoRelay) => {}
+            Err(err) => warn!("Hub lookup of {peer} failed: {err}"),
         }
+        let name = peer.to_string();
+        self.call(|reply| Command::Resolve { name, reply }).await?
+            .ok_or_else(|| Error::UnknownPeer(peer.to_string()))
     }
-    
-    // wait dialer and listener concurrently, every loop lasts 100 micro seconds
+
+    pub async fn shutdown(&self) -> Result<()> {
+        self.call(|reply| Command::Shutdown { reply }).await
+    }
+
+    /// Waits until the swarm task stops, i.e. after `shutdown`.
     pub async fn wait(&self) {
-        let mut guard = self.swarm.lock_arc().await;
-        
-        let mut delay = futures_timer::Delay::new(Duration::from_micros(100)).fuse();
-        loop { select! {
-            event = guard.next() => { match event.unwrap() {
-                SwarmEvent::Behaviour(IdentifyEvent(event)) => {
-                    info!("Identify {event:?}")
-                }
-                SwarmEvent::Behaviour(PingEvent(event)) => {
-                    info!("Ping {event:?}")
-                }
-                SwarmEvent::Behaviour(DcutrEvent(event)) => {
-                    info!("Dcutr {event:?}")
-                }
-                SwarmEvent::Behaviour(RelayClientEvent(event)) => {
-                    info!("Relay {event:?}")
-                }
-                SwarmEvent::Behaviour(_) => todo!(),
-                SwarmEvent::ConnectionEstablished {
-                    peer_id, endpoint, num_established: _, concurrent_dial_errors: _
-                } => {
-                    info!("Established connection to {peer_id:?} via {endpoint:?}");
-                },
-                SwarmEvent::ConnectionClosed {
-                    peer_id, endpoint, num_established: _, cause
-                } => {
-                    error!("Connection with {peer_id:?}@{endpoint:?} closed due to {cause:?}");
-                },
-                SwarmEvent::IncomingConnection {
-                    local_addr: _, send_back_addr
-                } => {
-                    debug!("Incoming connection from {send_back_addr}");
-                },
-                SwarmEvent::IncomingConnectionError {
-                    local_addr: _, send_back_addr, error
-                } => {
-                    error!("Incoming connection from {send_back_addr} error: {error}");
-                },
-                SwarmEvent::OutgoingConnectionError {
-                    peer_id, error
-                } => {
-                    error!("Outgoing connection error to {:?}: {:?}", peer_id, error);
-                }
-                SwarmEvent::BannedPeer {
-                    // peer_id, endpoint
-                    ..
-                } => todo!(),
-                SwarmEvent::NewListenAddr {
-                    listener_id, address
-                } => {
-                    info!("Listening on {listener_id:?}@{address}");
-                },
-                SwarmEvent::ExpiredListenAddr {
-                    listener_id, address
-                } => {
-                    info!("Stopped listening to {listener_id:?}@{address}");
-                },
-                SwarmEvent::ListenerClosed {
-                    listener_id, addresses: _, reason
-                } => {
-                    error!("Listener {listener_id:?} closed due to {reason:?}");
-                },
-                SwarmEvent::ListenerError {
-                    // listener_id, error
-                    ..
-                } => todo!(),
-                SwarmEvent::Dialing(peer_id) => {
-                    info!("Dailing {peer_id:?}");
-                }
-            } }
-            _ = delay => {
-                // Timeout invoked, thus stop listening to swarm events
-                break;
-            }
-        } }
+        if let Some(task) = self.task.lock().await.take() {
+            task.await;
+        }
+    }
+
+    async fn call<T>(&self, command: impl FnOnce(oneshot::Sender<T>) -> Command) -> Result<T> {
+        let (reply, response) = oneshot::channel();
+        self.commands.clone().send(command(reply)).await
+            .map_err(|_| Error::Shutdown)?;
+        response.await.map_err(|_| Error::Shutdown)
     }
 }
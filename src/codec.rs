@@ -0,0 +1,98 @@
+/// Length-prefixed JSON codec shared by the request-response protocols
+
+use async_trait::async_trait;
+use futures::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use libp2p::core::upgrade::{read_length_prefixed, write_length_prefixed, ProtocolName};
+use libp2p::request_response::RequestResponseCodec;
+use serde::{de::DeserializeOwned, Serialize};
+use std::io;
+use std::marker::PhantomData;
+
+/// Largest message accepted from the wire
+const MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+
+#[derive(Debug, Clone)]
+pub(crate) struct Protocol(pub &'static [u8]);
+
+impl ProtocolName for Protocol {
+    fn protocol_name(&self) -> &[u8] {
+        self.0
+    }
+}
+
+pub(crate) struct JsonCodec<Req, Resp> {
+    _marker: PhantomData<fn() -> (Req, Resp)>,
+}
+
+impl<Req, Resp> Default for JsonCodec<Req, Resp> {
+    fn default() -> Self {
+        Self { _marker: PhantomData }
+    }
+}
+
+impl<Req, Resp> Clone for JsonCodec<Req, Resp> {
+    fn clone(&self) -> Self {
+        Self::default()
+    }
+}
+
+async fn read_json<T, M>(io: &mut T) -> io::Result<M>
+where
+    T: AsyncRead + Unpin + Send,
+    M: DeserializeOwned,
+{
+    let bytes = read_length_prefixed(io, MAX_MESSAGE_SIZE).await?;
+    if bytes.is_empty() {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    serde_json::from_slice(&bytes).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+async fn write_json<T, M>(io: &mut T, message: M) -> io::Result<()>
+where
+    T: AsyncWrite + Unpin + Send,
+    M: Serialize,
+{
+    let bytes = serde_json::to_vec(&message).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    write_length_prefixed(io, bytes).await?;
+    io.close().await
+}
+
+#[async_trait]
+impl<Req, Resp> RequestResponseCodec for JsonCodec<Req, Resp>
+where
+    Req: Serialize + DeserializeOwned + Send,
+    Resp: Serialize + DeserializeOwned + Send,
+{
+    type Protocol = Protocol;
+    type Request = Req;
+    type Response = Resp;
+
+    async fn read_request<T>(&mut self, _: &Protocol, io: &mut T) -> io::Result<Req>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_json(io).await
+    }
+
+    async fn read_response<T>(&mut self, _: &Protocol, io: &mut T) -> io::Result<Resp>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_json(io).await
+    }
+
+    async fn write_request<T>(&mut self, _: &Protocol, io: &mut T, request: Req) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_json(io, request).await
+    }
+
+    async fn write_response<T>(&mut self, _: &Protocol, io: &mut T, response: Resp) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_json(io, response).await
+    }
+}
@@ -0,0 +1,291 @@
+/// Typed prompt commands, their parser, and the registry of application commands
+
+use async_trait::async_trait;
+use libp2p::PeerId;
+use std::path::PathBuf;
+use std::str::FromStr;
+use thiserror::Error;
+
+use crate::directory::DirectoryEntry;
+use crate::error::Error;
+use crate::Node;
+
+/// How a command is spelled, and explained by `help`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CommandSpec {
+    pub name: &'static str,
+    pub alias: Option<&'static str>,
+    /// Arguments, e.g. `<peer> <text>`
+    pub usage: &'static str,
+    pub help: &'static str,
+}
+
+impl CommandSpec {
+    pub fn matches(&self, word: &str) -> bool {
+        self.name == word || self.alias == Some(word)
+    }
+
+    fn usage(&self) -> String {
+        format!("{} {}", self.name, self.usage).trim_end().to_string()
+    }
+}
+
+const HELP: CommandSpec = CommandSpec { name: "help", alias: Some("h"), usage: "[command]", help: "show help of all or one command" };
+const QUIT: CommandSpec = CommandSpec { name: "quit", alias: Some("q"), usage: "", help: "shut down" };
+
+/// Commands of a client, `<peer>` being a PeerId or a username
+pub const CLIENT_COMMANDS: &[CommandSpec] = &[
+    CommandSpec { name: "relay", alias: Some("r"), usage: "[peerid]", help: "reserve on a relay, the configured hubs without peerid" },
+    CommandSpec { name: "dial", alias: Some("d"), usage: "<peer>", help: "connect to a peer through the relays" },
+    CommandSpec { name: "send", alias: Some("s"), usage: "<peer> <text>", help: "send a message" },
+    CommandSpec { name: "send-file", alias: Some("sf"), usage: "<peer> <path>", help: "offer a file" },
+    CommandSpec { name: "accept", alias: Some("a"), usage: "<transfer id>", help: "accept or resume an offered file" },
+    CommandSpec { name: "decline", alias: None, usage: "<transfer id>", help: "refuse an offered file" },
+    CommandSpec { name: "list", alias: Some("ls"), usage: "", help: "list peers registered on the hub" },
+    CommandSpec { name: "lookup", alias: Some("l"), usage: "<peer>", help: "find a peer by username or PeerId" },
+    CommandSpec { name: "peers", alias: Some("p"), usage: "", help: "show whether connected peers are relayed, upgrading, direct or failed" },
+    HELP,
+    QUIT,
+];
+
+/// Admin commands of a hub
+pub const HUB_COMMANDS: &[CommandSpec] = &[
+    CommandSpec { name: "list", alias: Some("ls"), usage: "", help: "list registered clients" },
+    CommandSpec { name: "reload-acl", alias: None, usage: "", help: "re-read the ACL file now" },
+    CommandSpec { name: "reservations", alias: Some("res"), usage: "", help: "list reservations with their age" },
+    CommandSpec { name: "circuits", alias: Some("c"), usage: "", help: "list relayed circuits with their age, libp2p doesn't count their bytes" },
+    CommandSpec { name: "kick", alias: None, usage: "<peerid>", help: "disconnect a peer, it may come back" },
+    CommandSpec { name: "ban", alias: None, usage: "<peerid>", help: "disconnect and refuse a peer until unban" },
+    CommandSpec { name: "unban", alias: None, usage: "<peerid>", help: "lift a ban, the ACL still applies" },
+    CommandSpec { name: "addrs", alias: None, usage: "", help: "print our listen addresses" },
+    HELP,
+    QUIT,
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Relay { relay_id: Option<PeerId> },
+    Dial { peer: String },
+    Send { peer: String, text: String },
+    SendFile { peer: String, path: PathBuf },
+    Accept { transfer_id: u64 },
+    Decline { transfer_id: u64 },
+    List,
+    Lookup { query: String },
+    Peers,
+    ReloadAcl,
+    Reservations,
+    Circuits,
+    Kick { peer_id: PeerId },
+    Ban { peer_id: PeerId },
+    Unban { peer_id: PeerId },
+    ListenAddrs,
+    Help { command: Option<String> },
+    Quit,
+}
+
+/// What the prompt does after a command
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Continue,
+    /// Continue, showing the command's output, e.g. a listing.
+    Output(String),
+    Quit,
+}
+
+#[derive(Debug, Error)]
+pub enum CommandError {
+    /// No command of the node is spelled like this.
+    #[error("unknown command {0:?}, try help")]
+    Unknown(String),
+    /// Arguments are missing, extra or malformed.
+    #[error("{reason}, usage: {usage}")]
+    Usage { usage: String, reason: String },
+    /// Command was understood but failed.
+    #[error(transparent)]
+    Failed(#[from] Error),
+}
+
+impl Command {
+    /// Parses `line` against `specs`, `None` for a blank line.
+    pub fn parse(line: &str, specs: &[CommandSpec]) -> Result<Option<Self>, CommandError> {
+        let (word, rest) = split_word(line);
+        if word.is_empty() {
+            return Ok(None);
+        }
+        let spec = specs.iter()
+            .find(|spec| spec.matches(word))
+            .ok_or_else(|| CommandError::Unknown(word.to_string()))?;
+        let usage = |reason: &str| CommandError::Usage { usage: spec.usage(), reason: reason.to_string() };
+        let transfer_id = |arg: Option<&str>| -> Result<u64, CommandError> {
+            arg.ok_or_else(|| usage("missing transfer id"))?
+                .parse()
+                .map_err(|_| usage("invalid transfer id"))
+        };
+
+        let mut args = rest.split_whitespace();
+        let mut peer_id = || -> Result<PeerId, CommandError> {
+            PeerId::from_str(args.next().ok_or_else(|| usage("missing PeerId"))?)
+                .map_err(|_| usage("invalid PeerId"))
+        };
+        let command = match spec.name {
+            "relay" => Command::Relay {
+                relay_id: args.next()
+                    .map(|id| PeerId::from_str(id).map_err(|_| usage("invalid PeerId")))
+                    .transpose()?,
+            },
+            "dial" => Command::Dial { peer: args.next().ok_or_else(|| usage("missing peer"))?.to_string() },
+            // Message and path keep their own spacing
+            "send" | "send-file" => {
+                let (peer, tail) = split_word(rest);
+                if peer.is_empty() || tail.is_empty() {
+                    return Err(usage("missing argument"));
+                }
+                return Ok(Some(match spec.name {
+                    "send" => Command::Send { peer: peer.to_string(), text: tail.to_string() },
+                    _ => Command::SendFile { peer: peer.to_string(), path: PathBuf::from(tail) },
+                }));
+            }
+            "accept" => Command::Accept { transfer_id: transfer_id(args.next())? },
+            "decline" => Command::Decline { transfer_id: transfer_id(args.next())? },
+            "list" => Command::List,
+            "peers" => Command::Peers,
+            "lookup" => Command::Lookup { query: args.next().ok_or_else(|| usage("missing peer"))?.to_string() },
+            "reload-acl" => Command::ReloadAcl,
+            "reservations" => Command::Reservations,
+            "circuits" => Command::Circuits,
+            "kick" => Command::Kick { peer_id: peer_id()? },
+            "ban" => Command::Ban { peer_id: peer_id()? },
+            "unban" => Command::Unban { peer_id: peer_id()? },
+            "addrs" => Command::ListenAddrs,
+            "help" => Command::Help { command: args.next().map(str::to_string) },
+            "quit" => Command::Quit,
+            name => return Err(CommandError::Unknown(name.to_string())),
+        };
+        if args.next().is_some() {
+            return Err(usage("too many arguments"));
+        }
+        Ok(Some(command))
+    }
+}
+
+/// Help of every command in `specs`, or of `command` alone.
+pub fn help(specs: &[CommandSpec], command: Option<&str>) -> Result<String, CommandError> {
+    let line = |spec: &CommandSpec| {
+        let name = match spec.alias {
+            Some(alias) => format!("{}|{alias} {}", spec.name, spec.usage),
+            None => format!("{} {}", spec.name, spec.usage),
+        };
+        format!("  {name:<30}{}", spec.help)
+    };
+    match command {
+        Some(command) => specs.iter()
+            .find(|spec| spec.matches(command))
+            .map(line)
+            .ok_or_else(|| CommandError::Unknown(command.to_string())),
+        None => Ok(specs.iter().map(line).fold("Commands:".to_string(), |help, line| help + "\n" + &line)),
+    }
+}
+
+/// Command an application adds to the prompt through `CommandRegistry`.
+#[async_trait]
+pub trait CustomCommand: Send + Sync {
+    fn spec(&self) -> CommandSpec;
+
+    /// Runs with everything after the command word.
+    async fn run(&self, node: &Node, args: &str) -> Result<Outcome, CommandError>;
+}
+
+/// Built-in commands of the node plus registered ones, the latter
+/// shadowing built-ins of the same name.
+#[derive(Default)]
+pub struct CommandRegistry {
+    custom: Vec<Box<dyn CustomCommand>>,
+}
+
+impl CommandRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register(&mut self, command: impl CustomCommand + 'static) {
+        self.custom.push(Box::new(command));
+    }
+
+    /// Every command `node` understands, registered ones first.
+    pub fn specs(&self, node: &Node) -> Vec<CommandSpec> {
+        let custom: Vec<CommandSpec> = self.custom.iter().map(|command| command.spec()).collect();
+        let builtin = node.commands().iter().filter(|spec| !custom.iter().any(|own| own.name == spec.name));
+        custom.iter().copied().chain(builtin.copied()).collect()
+    }
+
+    pub async fn execute(&self, node: &Node, line: &str) -> Result<Outcome, CommandError> {
+        let (word, args) = split_word(line);
+        if let Some(command) = self.custom.iter().find(|command| command.spec().matches(word)) {
+            return command.run(node, args).await;
+        }
+        match Command::parse(line, node.commands())? {
+            Some(Command::Help { command }) => {
+                Ok(Outcome::Output(help(&self.specs(node), command.as_deref())?))
+            }
+            Some(command) => node.run(command).await,
+            None => Ok(Outcome::Continue),
+        }
+    }
+}
+
+/// First word of `line` and the rest, both trimmed.
+fn split_word(line: &str) -> (&str, &str) {
+    let line = line.trim();
+    match line.split_once(char::is_whitespace) {
+        Some((word, rest)) => (word, rest.trim_start()),
+        None => (line, ""),
+    }
+}
+
+pub(crate) fn lines<T>(items: Vec<T>, none: &str, line: impl Fn(T) -> String) -> Outcome {
+    if items.is_empty() {
+        return Outcome::Output(none.to_string());
+    }
+    Outcome::Output(items.into_iter().map(line).collect::<Vec<_>>().join("\n"))
+}
+
+pub(crate) fn entry_lines(entries: Vec<DirectoryEntry>) -> Outcome {
+    lines(entries, "No peer found", |entry| {
+        let name = entry.username.as_deref().unwrap_or("-");
+        format!("{} {name} {}", entry.peer_id, entry.circuit_addr)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Command, CommandError, CLIENT_COMMANDS, HUB_COMMANDS};
+    use libp2p::PeerId;
+
+    #[test]
+    fn parse_commands() {
+        let parse = |line| Command::parse(line, CLIENT_COMMANDS);
+        let peer_id = PeerId::random();
+
+        assert_eq!(parse("  ").unwrap(), None);
+        assert_eq!(parse("r").unwrap(), Some(Command::Relay { relay_id: None }));
+        assert_eq!(parse(&format!("relay {peer_id}")).unwrap(), Some(Command::Relay { relay_id: Some(peer_id) }));
+        assert_eq!(
+            parse("s bob  hello   there ").unwrap(),
+            Some(Command::Send { peer: "bob".to_string(), text: "hello   there".to_string() }),
+        );
+        assert_eq!(parse("accept 7").unwrap(), Some(Command::Accept { transfer_id: 7 }));
+        assert_eq!(parse("p").unwrap(), Some(Command::Peers));
+
+        assert!(matches!(parse("relay nobody"), Err(CommandError::Usage { .. })));
+        assert!(matches!(parse("send bob"), Err(CommandError::Usage { .. })));
+        assert!(matches!(parse("decline"), Err(CommandError::Usage { .. })));
+        assert!(matches!(parse("list all"), Err(CommandError::Usage { .. })));
+        assert!(matches!(parse("fly"), Err(CommandError::Unknown(_))));
+
+        assert_eq!(Command::parse("reload-acl", HUB_COMMANDS).unwrap(), Some(Command::ReloadAcl));
+        assert!(matches!(Command::parse("dial bob", HUB_COMMANDS), Err(CommandError::Unknown(_))));
+        assert_eq!(Command::parse(&format!("kick {peer_id}"), HUB_COMMANDS).unwrap(), Some(Command::Kick { peer_id }));
+        assert!(matches!(Command::parse("ban", HUB_COMMANDS), Err(CommandError::Usage { .. })));
+    }
+}
@@ -6,31 +6,249 @@ use serde::Deserialize;
 use libp2p::Multiaddr;
 use libp2p::PeerId;
 use libp2p::multiaddr::Protocol;
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use libp2p::pnet::PreSharedKey;
+use libp2p::websocket::tls;
+use libp2p::relay::v2::relay::Config as RelayConfig;
+use libp2p::relay::v2::relay::rate_limiter::{self, GenericRateLimiterConfig, RateLimiter};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::num::NonZeroU32;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::acl::AccessList;
+use crate::error::{Error, Result};
 
 #[derive(Debug, Deserialize, PartialEq)]
 pub struct Conf {
     pub role: String,
     pub use_ipv6: bool,
+    /// swarm.key file, only nodes sharing it can connect when set
+    psk: Option<String>,
+    /// Unix socket path of the JSON control API, off when unset
+    control_socket: Option<String>,
+    /// `ip:port` serving Prometheus metrics at `/metrics`, off when unset
+    metrics_addr: Option<String>,
     hub: HubOpt,
     client: ClientOpt,
 }
 
-#[derive(Debug, Deserialize, PartialEq)]
+#[derive(Debug, Default, Deserialize, PartialEq)]
 pub struct HubOpt {
     listen_port: u16,
+    key_path: Option<String>,
+    /// Comma separated PeerIds, the only ones accepted when set
+    allow: Option<String>,
+    /// Comma separated PeerIds always refused
+    deny: Option<String>,
+    /// ACL file merged with `allow`/`deny`, reloaded when it changes
+    acl_path: Option<String>,
+    /// Also listen for WebSocket connections on this port
+    ws_port: Option<u16>,
+    /// DER encoded certificate and private key, turning `ws_port` into secure WebSocket
+    tls_cert_path: Option<String>,
+    tls_key_path: Option<String>,
+    /// `[hub.relay]`, which the config crate nests under `hub`
+    #[serde(default)]
+    relay: RelayOpt,
 }
 
+/// Relay limits of the hub, defaults mirror libp2p's relay defaults.
+/// A rate of 0 disables that rate limiter.
 #[derive(Debug, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct RelayOpt {
+    max_reservations: usize,
+    max_reservations_per_peer: usize,
+    reservation_duration_secs: u64,
+    max_circuits: usize,
+    max_circuits_per_peer: usize,
+    max_circuit_duration_secs: u64,
+    max_circuit_bytes: u64,
+    reservation_rate_per_peer: u32,
+    reservation_rate_per_ip: u32,
+    circuit_rate_per_peer: u32,
+    circuit_rate_per_ip: u32,
+    rate_per_peer_interval_secs: u64,
+    rate_per_ip_interval_secs: u64,
+}
+
+impl Default for RelayOpt {
+    fn default() -> Self {
+        Self {
+            max_reservations: 128,
+            max_reservations_per_peer: 4,
+            reservation_duration_secs: 60 * 60,
+            max_circuits: 16,
+            max_circuits_per_peer: 4,
+            max_circuit_duration_secs: 2 * 60,
+            max_circuit_bytes: 1 << 17,
+            reservation_rate_per_peer: 30,
+            reservation_rate_per_ip: 60,
+            circuit_rate_per_peer: 30,
+            circuit_rate_per_ip: 60,
+            rate_per_peer_interval_secs: 2 * 60,
+            rate_per_ip_interval_secs: 60 * 60,
+        }
+    }
+}
+
+impl RelayOpt {
+    fn validate(&self) -> Result<()> {
+        let invalid = |reason: &str| Err(Error::Config(format!("[hub.relay] {reason}")));
+        if self.max_reservations == 0 || self.max_circuits == 0 {
+            return invalid("max_reservations and max_circuits must be positive");
+        }
+        if self.max_reservations_per_peer == 0 || self.max_reservations_per_peer > self.max_reservations {
+            return invalid("max_reservations_per_peer must be within 1..=max_reservations");
+        }
+        if self.max_circuits_per_peer == 0 || self.max_circuits_per_peer > self.max_circuits {
+            return invalid("max_circuits_per_peer must be within 1..=max_circuits");
+        }
+        if self.reservation_duration_secs == 0 || self.max_circuit_duration_secs == 0 {
+            return invalid("durations must be positive");
+        }
+        if self.rate_per_peer_interval_secs == 0 || self.rate_per_ip_interval_secs == 0 {
+            return invalid("rate limiter intervals must be positive");
+        }
+        Ok(())
+    }
+
+    fn to_config(&self) -> RelayConfig {
+        let per_peer = |rate| limiter(rate, self.rate_per_peer_interval_secs, rate_limiter::new_per_peer);
+        let per_ip = |rate| limiter(rate, self.rate_per_ip_interval_secs, rate_limiter::new_per_ip);
+        RelayConfig {
+            max_reservations: self.max_reservations,
+            max_reservations_per_peer: self.max_reservations_per_peer,
+            reservation_duration: Duration::from_secs(self.reservation_duration_secs),
+            reservation_rate_limiters: per_peer(self.reservation_rate_per_peer)
+                .into_iter()
+                .chain(per_ip(self.reservation_rate_per_ip))
+                .collect(),
+            max_circuits: self.max_circuits,
+            max_circuits_per_peer: self.max_circuits_per_peer,
+            max_circuit_duration: Duration::from_secs(self.max_circuit_duration_secs),
+            max_circuit_bytes: self.max_circuit_bytes,
+            circuit_src_rate_limiters: per_peer(self.circuit_rate_per_peer)
+                .into_iter()
+                .chain(per_ip(self.circuit_rate_per_ip))
+                .collect(),
+        }
+    }
+}
+
+fn limiter(
+    rate: u32,
+    interval_secs: u64,
+    new: fn(GenericRateLimiterConfig) -> Box<dyn RateLimiter>,
+) -> Option<Box<dyn RateLimiter>> {
+    NonZeroU32::new(rate).map(|limit| new(GenericRateLimiterConfig {
+        limit,
+        interval: Duration::from_secs(interval_secs),
+    }))
+}
+
+fn parse_peer_ids(list: Option<&str>) -> Result<Vec<PeerId>> {
+    list.unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|peer| !peer.is_empty())
+        .map(|peer| PeerId::from_str(peer).map_err(|err| Error::Config(format!("{peer}: {err}"))))
+        .collect()
+}
+
+#[derive(Debug, Default, Deserialize, PartialEq)]
 pub struct ClientOpt {
-    hub_ip: IpAddr,
+    username: Option<String>,
+    /// Comma separated IPs of the hub, one per family it's reachable over
+    #[serde(default)]
+    hub_ip: String,
+    /// Host name of the hub, used instead of `hub_ip`
+    hub_host: Option<String>,
+    #[serde(default)]
     hub_port: u16,
+    /// Complete hub multiaddr, e.g. `/dns4/relay.example.com/tcp/8443/p2p/<id>`,
+    /// replacing `hub_ip`, `hub_host`, `hub_port` and `hub_websocket`
+    hub_addr: Option<String>,
+    /// PeerId of the hub, lets the client reserve on it at startup
+    hub_peer_id: Option<String>,
+    /// Comma separated multiaddrs, each ending in `/p2p/<id>`, of more hubs to fail over to
+    hubs: Option<String>,
+    /// How many hubs to hold a reservation on at once, 2 by default
+    hub_reservations: Option<usize>,
+    key_path: Option<String>,
+    /// Where received files go, created when missing
+    download_dir: Option<String>,
+    /// `ws` or `wss` to reach the hub over WebSocket, `hub_port` being its WebSocket port
+    hub_websocket: Option<String>,
+    /// Close relayed connections to a peer once hole punching got us a direct one
+    #[serde(default)]
+    close_relayed: bool,
 }
 
 impl Conf {
-    pub fn new(config_path: &str) -> Self {
-        let setting = Config::builder().add_source(File::with_name(config_path)).build().unwrap();
-        setting.try_deserialize().unwrap()
+    pub fn new(config_path: &str) -> Result<Self> {
+        Self::with_overrides(config_path, [])
+    }
+
+    /// Reads `config_path`, then replaces the given keys, e.g. `("client.hub_addr", addr)`.
+    pub fn with_overrides<'a>(
+        config_path: &str,
+        overrides: impl IntoIterator<Item = (&'a str, String)>,
+    ) -> Result<Self> {
+        let mut builder = Config::builder().add_source(File::with_name(config_path));
+        for (key, value) in overrides {
+            builder = builder.set_override(key, value)?;
+        }
+        let conf: Self = builder.build()?.try_deserialize()?;
+        conf.validate()?;
+        Ok(conf)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if let Some(username) = &self.client.username {
+            if username.is_empty() || username.contains(|c: char| c.is_whitespace() || c == '/') {
+                return Err(Error::Config(format!("Invalid username {username:?}, no whitespace or '/' allowed")));
+            }
+        }
+        match self.role.as_str() {
+            "hub" => {
+                self.get_access_list()?;
+                if let Some(path) = &self.hub.acl_path {
+                    // The hub refuses to start without its ACL, better tell right away
+                    let text = std::fs::read_to_string(path)
+                        .map_err(|err| Error::Config(format!("acl_path {path}: {err}")))?;
+                    AccessList::parse(&text)?;
+                }
+            }
+            "client" => {
+                let client = &self.client;
+                let main_hub = client.hub_addr.is_some() || client.hub_host.is_some() || !client.hub_ip.is_empty();
+                // A `hubs` list alone is enough
+                if main_hub || client.hubs.is_none() {
+                    self.hub_address()?;
+                }
+                self.hub_peer_id()?;
+                self.extra_hubs()?;
+                if self.client.hub_reservations == Some(0) {
+                    return Err(Error::Config("hub_reservations must be positive".to_string()));
+                }
+            }
+            _ => {}
+        }
+        if let Some(websocket) = &self.client.hub_websocket {
+            if websocket != "ws" && websocket != "wss" {
+                return Err(Error::Config(format!("Invalid hub_websocket {websocket:?}, expected ws or wss")));
+            }
+        }
+        if self.hub.tls_cert_path.is_some() != self.hub.tls_key_path.is_some() {
+            return Err(Error::Config("tls_cert_path and tls_key_path must be set together".to_string()));
+        }
+        if cfg!(not(unix)) && self.control_socket.is_some() {
+            return Err(Error::Config("control_socket needs Unix sockets".to_string()));
+        }
+        self.get_metrics_addr()?;
+        self.hub.relay.validate()
     }
 }
 
@@ -51,35 +269,401 @@ impl Conf {
         }
     }
 
-    pub fn get_bind_address(&self) -> Multiaddr {
+    /// Display name advertised to other peers, clients only.
+    pub fn get_username(&self) -> Option<&str> {
+        if let "client" = self.role.as_str() {
+            self.client.username.as_deref()
+        } else {
+            None
+        }
+    }
+
+    /// Where received files are stored, `downloads` by default.
+    pub fn get_download_dir(&self) -> PathBuf {
+        PathBuf::from(self.client.download_dir.as_deref().unwrap_or("downloads"))
+    }
+
+    /// Where the node identity is persisted, if configured for the current role.
+    pub fn get_key_path(&self) -> Option<&str> {
+        match self.role.as_str() {
+            "hub" => self.hub.key_path.as_deref(),
+            "client" => self.client.key_path.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// Where to serve the control API, if anywhere.
+    pub fn get_control_socket(&self) -> Option<&str> {
+        self.control_socket.as_deref()
+    }
+
+    /// Where to serve Prometheus metrics, if anywhere.
+    pub fn get_metrics_addr(&self) -> Result<Option<SocketAddr>> {
+        self.metrics_addr.as_deref()
+            .map(|addr| SocketAddr::from_str(addr)
+                .map_err(|_| Error::Config(format!("Invalid metrics_addr {addr:?}, expected ip:port"))))
+            .transpose()
+    }
+
+    /// Pre-shared key of our private network, if any.
+    pub fn get_psk(&self) -> Result<Option<PreSharedKey>> {
+        let path = match &self.psk {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        let text = std::fs::read_to_string(path).map_err(Error::Key)?;
+        let psk = PreSharedKey::from_str(&text)
+            .map_err(|err| Error::Config(format!("{path}: invalid swarm key, {err}")))?;
+        info!("Private network enabled, key fingerprint {}", psk.fingerprint());
+        Ok(Some(psk))
+    }
+
+    /// Peers allowed and denied by the `[hub]` section itself.
+    pub fn get_access_list(&self) -> Result<AccessList> {
+        Ok(AccessList::new(
+            parse_peer_ids(self.hub.allow.as_deref())?,
+            parse_peer_ids(self.hub.deny.as_deref())?,
+        ))
+    }
+
+    /// ACL file the hub watches for changes.
+    pub fn get_acl_path(&self) -> Option<&str> {
+        self.hub.acl_path.as_deref()
+    }
+
+    /// Relay limits the hub enforces on reservations and circuits.
+    pub fn get_relay_config(&self) -> RelayConfig {
+        self.hub.relay.to_config()
+    }
+
+    /// Addresses to listen on, one per IP family, IPv6 only with `use_ipv6`.
+    /// The hub adds its WebSocket listeners, `/wss` when a certificate is configured.
+    pub fn get_bind_addresses(&self) -> Vec<Multiaddr> {
         // Listen on all interfaces
+        let mut ips = vec![IpAddr::from(Ipv4Addr::UNSPECIFIED)];
+        if self.use_ipv6 {
+            ips.push(IpAddr::from(Ipv6Addr::UNSPECIFIED));
+        }
         let port = self.get_bind_port();
-        match self.use_ipv6 {
-            true => Multiaddr::empty()
-                .with(Protocol::from(Ipv4Addr::UNSPECIFIED))
-                .with(Protocol::Tcp(port))
-                .with(Protocol::from(Ipv6Addr::UNSPECIFIED)),
-            false => Multiaddr::empty()
-                .with(Protocol::from(Ipv4Addr::UNSPECIFIED))
-                .with(Protocol::Tcp(port)),
+        let mut addrs: Vec<Multiaddr> = ips.iter()
+            .map(|ip| Multiaddr::from(*ip).with(Protocol::Tcp(port)))
+            .collect();
+        if let ("hub", Some(ws_port)) = (self.role.as_str(), self.hub.ws_port) {
+            let ws = match self.hub.tls_cert_path {
+                Some(_) => Protocol::Wss("/".into()),
+                None => Protocol::Ws("/".into()),
+            };
+            addrs.extend(ips.iter().map(|ip| Multiaddr::from(*ip).with(Protocol::Tcp(ws_port)).with(ws.clone())));
         }
+        addrs
+    }
+
+    /// TLS setup of the hub's secure WebSocket listener.
+    pub fn get_tls_config(&self) -> Result<Option<tls::Config>> {
+        let (cert_path, key_path) = match (&self.hub.tls_cert_path, &self.hub.tls_key_path) {
+            (Some(cert_path), Some(key_path)) => (cert_path, key_path),
+            _ => return Ok(None),
+        };
+        let cert = std::fs::read(cert_path).map_err(Error::Key)?;
+        let key = std::fs::read(key_path).map_err(Error::Key)?;
+        tls::Config::new(tls::PrivateKey::new(key), [tls::Certificate::new(cert)])
+            .map(Some)
+            .map_err(|err| Error::Config(format!("{cert_path}: {err}")))
+    }
+
+    /// Hub IP of the family we use, IPv6 preferred with `use_ipv6`.
+    fn hub_ip(&self) -> Result<IpAddr> {
+        let ips = self.client.hub_ip
+            .split(',')
+            .map(str::trim)
+            .filter(|ip| !ip.is_empty())
+            .map(|ip| match IpAddr::from_str(ip) {
+                // IPv4-mapped addresses are dialed as plain IPv4
+                Ok(IpAddr::V6(ip)) => Ok(ip.to_ipv4_mapped().map_or(IpAddr::V6(ip), IpAddr::V4)),
+                Ok(ip) => Ok(ip),
+                Err(err) => Err(Error::Config(format!("Invalid hub_ip {ip:?}: {err}"))),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let ipv4 = ips.iter().find(|ip| ip.is_ipv4());
+        let ipv6 = ips.iter().find(|ip| ip.is_ipv6());
+        match (self.use_ipv6, ipv4, ipv6) {
+            (true, _, Some(ip)) | (_, Some(ip), _) => Ok(*ip),
+            (false, None, Some(_)) => Err(Error::Config("hub_ip only has IPv6 addresses, set use_ipv6".to_string())),
+            _ => Err(Error::Config("hub_ip is empty".to_string())),
+        }
+    }
+
+    /// Hub address without its `/p2p` part, from the first of `hub_addr`,
+    /// `hub_host` and `hub_ip` that is set.
+    fn hub_address(&self) -> Result<Multiaddr> {
+        if let Some(addr) = &self.client.hub_addr {
+            let mut addr = Multiaddr::from_str(addr)
+                .map_err(|err| Error::Config(format!("Invalid hub_addr {addr:?}: {err}")))?;
+            if let Some(Protocol::P2p(_)) = addr.iter().last() {
+                addr.pop();
+            }
+            return Ok(addr);
+        }
+        if self.client.hub_port == 0 {
+            return Err(Error::Config("hub_port is required unless hub_addr is set".to_string()));
+        }
+        let addr = match &self.client.hub_host {
+            // Let the resolver pick the family when IPv6 is on
+            Some(host) if self.use_ipv6 => Multiaddr::empty().with(Protocol::Dns(host.into())),
+            Some(host) => Multiaddr::empty().with(Protocol::Dns4(host.into())),
+            None => Multiaddr::from(self.hub_ip()?),
+        };
+        let addr = addr.with(Protocol::Tcp(self.client.hub_port));
+        Ok(match self.client.hub_websocket.as_deref() {
+            Some("wss") => addr.with(Protocol::Wss("/".into())),
+            Some(_) => addr.with(Protocol::Ws("/".into())),
+            None => addr,
+        })
+    }
+
+    /// Hub PeerId from `hub_peer_id` or the `/p2p` part of `hub_addr`.
+    fn hub_peer_id(&self) -> Result<Option<PeerId>> {
+        let addr = self.client.hub_addr.as_deref().and_then(|addr| Multiaddr::from_str(addr).ok());
+        let from_addr = match addr.as_ref().and_then(|addr| addr.iter().last()) {
+            Some(Protocol::P2p(hash)) => Some(PeerId::from_multihash(hash)
+                .map_err(|_| Error::Config("Invalid PeerId in hub_addr".to_string()))?),
+            _ => None,
+        };
+        let configured = parse_peer_ids(self.client.hub_peer_id.as_deref())?;
+        match (configured.as_slice(), from_addr) {
+            ([], peer_id) => Ok(peer_id),
+            ([peer_id], None) => Ok(Some(*peer_id)),
+            ([peer_id], Some(other)) if *peer_id == other => Ok(Some(other)),
+            ([_], Some(_)) => Err(Error::Config("hub_peer_id doesn't match the PeerId of hub_addr".to_string())),
+            _ => Err(Error::Config("hub_peer_id takes a single PeerId".to_string())),
+        }
+    }
+
+    /// PeerId of the hub to reserve on at startup, if configured.
+    pub fn get_hub_peer_id(&self) -> Option<PeerId> {
+        match self.role.as_str() {
+            "client" => self.hub_peer_id().ok().flatten(),
+            _ => None,
+        }
+    }
+
+    fn extra_hubs(&self) -> Result<Vec<(PeerId, Multiaddr)>> {
+        self.client.hubs.as_deref().unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|addr| !addr.is_empty())
+            .map(|addr| {
+                let invalid = |reason: &str| Error::Config(format!("Invalid hub {addr:?}: {reason}"));
+                let addr = Multiaddr::from_str(addr).map_err(|err| invalid(&err.to_string()))?;
+                match addr.iter().last() {
+                    Some(Protocol::P2p(hash)) => {
+                        let peer_id = PeerId::from_multihash(hash).map_err(|_| invalid("bad PeerId"))?;
+                        Ok((peer_id, addr))
+                    }
+                    _ => Err(invalid("missing /p2p/<peerid>")),
+                }
+            })
+            .collect()
+    }
+
+    /// Hubs to reserve on, the main one first, then `hubs` in order.
+    pub fn get_hubs(&self) -> Vec<(PeerId, Multiaddr)> {
+        let main = self.get_hub_peer_id()
+            .and_then(|relay_id| Some((relay_id, self.get_relay_address(relay_id)?)));
+        let mut hubs: Vec<(PeerId, Multiaddr)> = main.into_iter().collect();
+        if let "client" = self.role.as_str() {
+            for (relay_id, addr) in self.extra_hubs().unwrap_or_default() {
+                if !hubs.iter().any(|(hub_id, _)| *hub_id == relay_id) {
+                    hubs.push((relay_id, addr));
+                }
+            }
+        }
+        hubs
+    }
+
+    /// How many hubs the client keeps a reservation on.
+    pub fn get_hub_reservations(&self) -> usize {
+        self.client.hub_reservations.unwrap_or(2)
+    }
+
+    /// Whether relayed connections go once a direct one is up.
+    pub fn get_close_relayed(&self) -> bool {
+        self.client.close_relayed
     }
 
     pub fn get_relay_address(&self, relay_id: PeerId) -> Option<Multiaddr> {
         if let "client" = self.role.as_str() {
-            // let relay_ip = match self.use_ipv6 {
-            //     true => self.client.hub_ip.parse::<Ipv6Addr>().unwrap().into(),
-            //     false => self.client.hub_ip.parse::<Ipv4Addr>().unwrap().into(),
-            // };
-            let relay_ip = self.client.hub_ip.into();
-            Some(
-                Multiaddr::empty()
-                .with(relay_ip)
-                .with(Protocol::Tcp(self.client.hub_port))
-                .with(Protocol::P2p(relay_id.into()))
-            )
+            Some(self.hub_address().ok()?.with(Protocol::P2p(relay_id.into())))
         } else {
             None
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{ClientOpt, Conf, HubOpt, RelayOpt};
+    use config::{Config, File, FileFormat};
+    use libp2p::{Multiaddr, PeerId};
+
+    fn conf(role: &str, use_ipv6: bool, hub_ip: &str) -> Conf {
+        Conf {
+            role: role.to_string(),
+            use_ipv6,
+            psk: None,
+            control_socket: None,
+            metrics_addr: None,
+            hub: HubOpt { listen_port: 8443, ..Default::default() },
+            client: ClientOpt { hub_ip: hub_ip.to_string(), hub_port: 8443, ..Default::default() },
+        }
+    }
+
+    fn addrs(addrs: &[&str]) -> Vec<Multiaddr> {
+        addrs.iter().map(|addr| addr.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn bind_addresses_per_family() {
+        assert_eq!(conf("hub", false, "").get_bind_addresses(), addrs(&["/ip4/0.0.0.0/tcp/8443"]));
+        assert_eq!(
+            conf("hub", true, "").get_bind_addresses(),
+            addrs(&["/ip4/0.0.0.0/tcp/8443", "/ip6/::/tcp/8443"]),
+        );
+        assert_eq!(conf("client", false, "127.0.0.1").get_bind_addresses(), addrs(&["/ip4/0.0.0.0/tcp/0"]));
+        assert_eq!(
+            conf("client", true, "127.0.0.1").get_bind_addresses(),
+            addrs(&["/ip4/0.0.0.0/tcp/0", "/ip6/::/tcp/0"]),
+        );
+
+        let mut hub = conf("hub", true, "");
+        hub.hub.ws_port = Some(8080);
+        assert_eq!(
+            hub.get_bind_addresses(),
+            addrs(&["/ip4/0.0.0.0/tcp/8443", "/ip6/::/tcp/8443", "/ip4/0.0.0.0/tcp/8080/ws", "/ip6/::/tcp/8080/ws"]),
+        );
+    }
+
+    #[test]
+    fn relay_address_family() {
+        let relay_id = PeerId::random();
+        let relay_ip = |use_ipv6, hub_ip| {
+            conf("client", use_ipv6, hub_ip).get_relay_address(relay_id).map(|addr| {
+                addr.to_string().trim_end_matches(&format!("/tcp/8443/p2p/{relay_id}")).to_string()
+            })
+        };
+        let (ipv4, ipv6) = (Some("/ip4/10.0.0.1".to_string()), Some("/ip6/fd00::1".to_string()));
+
+        assert_eq!(relay_ip(false, "10.0.0.1"), ipv4);
+        assert_eq!(relay_ip(true, "10.0.0.1"), ipv4);
+        assert_eq!(relay_ip(false, "fd00::1"), None);
+        assert_eq!(relay_ip(true, "fd00::1"), ipv6);
+        assert_eq!(relay_ip(false, "fd00::1, 10.0.0.1"), ipv4);
+        assert_eq!(relay_ip(true, "10.0.0.1, fd00::1"), ipv6);
+        assert_eq!(relay_ip(false, "::ffff:10.0.0.1"), ipv4);
+        assert_eq!(relay_ip(true, "not-an-ip"), None);
+        assert_eq!(relay_ip(true, ""), None);
+
+        assert!(conf("client", false, "fd00::1").validate().is_err());
+        assert!(conf("client", true, "fd00::1").validate().is_ok());
+        assert!(conf("hub", false, "fd00::1").validate().is_ok());
+        assert_eq!(conf("hub", true, "10.0.0.1").get_relay_address(relay_id), None);
+    }
+
+    #[test]
+    fn hub_by_host_or_multiaddr() {
+        let relay_id = PeerId::random();
+
+        let mut client = conf("client", false, "");
+        client.client.hub_host = Some("relay.example.com".to_string());
+        assert_eq!(
+            client.get_relay_address(relay_id),
+            Some(format!("/dns4/relay.example.com/tcp/8443/p2p/{relay_id}").parse().unwrap()),
+        );
+        client.use_ipv6 = true;
+        assert_eq!(
+            client.get_relay_address(relay_id),
+            Some(format!("/dns/relay.example.com/tcp/8443/p2p/{relay_id}").parse().unwrap()),
+        );
+        assert_eq!(client.get_hub_peer_id(), None);
+
+        let mut client = conf("client", false, "");
+        client.client.hub_port = 0;
+        client.client.hub_addr = Some(format!("/dns4/relay.example.com/tcp/443/wss/p2p/{relay_id}"));
+        assert!(client.validate().is_ok());
+        assert_eq!(client.get_hub_peer_id(), Some(relay_id));
+        assert_eq!(
+            client.get_relay_address(relay_id),
+            Some(format!("/dns4/relay.example.com/tcp/443/wss/p2p/{relay_id}").parse().unwrap()),
+        );
+
+        client.client.hub_peer_id = Some(relay_id.to_string());
+        assert!(client.validate().is_ok());
+        client.client.hub_peer_id = Some(PeerId::random().to_string());
+        assert!(client.validate().is_err());
+
+        client.client.hub_addr = None;
+        assert!(client.validate().is_err());
+    }
+
+    #[test]
+    fn hub_list() {
+        let (main, backup) = (PeerId::random(), PeerId::random());
+        let mut client = conf("client", false, "10.0.0.1");
+        client.client.hub_peer_id = Some(main.to_string());
+        client.client.hubs = Some(format!("/ip4/10.0.0.2/tcp/8443/p2p/{backup}, /ip4/10.0.0.1/tcp/8443/p2p/{main}"));
+        assert!(client.validate().is_ok());
+        assert_eq!(
+            client.get_hubs(),
+            vec![
+                (main, format!("/ip4/10.0.0.1/tcp/8443/p2p/{main}").parse().unwrap()),
+                (backup, format!("/ip4/10.0.0.2/tcp/8443/p2p/{backup}").parse().unwrap()),
+            ],
+        );
+
+        client.client.hubs = Some("/ip4/10.0.0.2/tcp/8443".to_string());
+        assert!(client.validate().is_err());
+
+        let mut client = conf("client", false, "");
+        client.client.hubs = Some(format!("/ip4/10.0.0.2/tcp/8443/p2p/{backup}"));
+        assert!(client.validate().is_ok());
+        assert_eq!(client.get_hubs().len(), 1);
+    }
+
+    #[test]
+    fn relay_defaults_are_valid() {
+        let opt = RelayOpt::default();
+        assert!(opt.validate().is_ok());
+        let config = opt.to_config();
+        assert_eq!(config.max_reservations, 128);
+        assert_eq!(config.reservation_rate_limiters.len(), 2);
+    }
+
+    #[test]
+    fn relay_limits_are_checked() {
+        let opt = RelayOpt { max_circuits_per_peer: 32, ..Default::default() };
+        assert!(opt.validate().is_err());
+        let opt = RelayOpt { reservation_duration_secs: 0, ..Default::default() };
+        assert!(opt.validate().is_err());
+
+        let opt = RelayOpt { circuit_rate_per_peer: 0, circuit_rate_per_ip: 0, ..Default::default() };
+        assert!(opt.validate().is_ok());
+        assert!(opt.to_config().circuit_src_rate_limiters.is_empty());
+    }
+
+    #[test]
+    fn relay_section_is_read() {
+        let ini = "role = hub\nuse_ipv6 = false\n\n[hub]\nlisten_port = 8443\n\n\
+                   [hub.relay]\nmax_reservations = 7\nmax_reservations_per_peer = 1\nmax_circuit_bytes = 4096\n\n\
+                   [client]\n";
+        let conf: Conf = Config::builder()
+            .add_source(File::from_str(ini, FileFormat::Ini))
+            .build().unwrap()
+            .try_deserialize().unwrap();
+        assert!(conf.validate().is_ok());
+        let config = conf.get_relay_config();
+        assert_eq!(config.max_reservations, 7);
+        assert_eq!(config.max_reservations_per_peer, 1);
+        assert_eq!(config.max_circuit_bytes, 4096);
+        assert_eq!(config.max_circuits, 16);
+    }
+}
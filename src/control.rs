@@ -0,0 +1,250 @@
+/// Control API over a Unix socket, one JSON request and one JSON response per line
+
+use async_std::io::BufReader;
+use async_std::os::unix::net::{UnixListener, UnixStream};
+use futures::io::{AsyncBufReadExt, AsyncWriteExt};
+use futures::stream::StreamExt;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::path::Path;
+
+use crate::command::{CommandRegistry, Outcome};
+use crate::error::{Error, Result};
+use crate::Node;
+
+/// e.g. `{"method":"execute","line":"dial bob"}` or `{"method":"status"}`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "method", rename_all = "snake_case")]
+pub enum ControlRequest {
+    /// Runs a prompt command line, its output comes back in `Done`.
+    Execute { line: String },
+    Status,
+    /// Clients registered on the hub directory.
+    DirectoryPeers,
+    /// Whether connected peers are relayed or direct, clients only.
+    PeerConnections,
+    Reservations,
+    Circuits,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum ControlResponse {
+    /// Command ran, `quit` if the node is shutting down because of it.
+    Done {
+        quit: bool,
+        /// What the command printed at the prompt, if anything
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        output: Option<String>,
+    },
+    Status {
+        role: String,
+        peer_id: String,
+        listen_addrs: Vec<String>,
+        connected_peers: Vec<String>,
+    },
+    DirectoryPeers { peers: Vec<PeerEntry> },
+    PeerConnections { connections: Vec<ConnectionEntry> },
+    Reservations { reservations: Vec<ReservationEntry> },
+    Circuits { circuits: Vec<CircuitEntry> },
+    Error { message: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PeerEntry {
+    pub peer_id: String,
+    pub username: Option<String>,
+    pub circuit_addr: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ConnectionEntry {
+    pub peer_id: String,
+    pub username: Option<String>,
+    /// `relayed`, `upgrading`, `direct` or `failed (<reason>)`
+    pub state: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReservationEntry {
+    pub peer_id: String,
+    pub username: Option<String>,
+    pub age_secs: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CircuitEntry {
+    pub src_peer_id: String,
+    pub dst_peer_id: String,
+    pub age_secs: u64,
+}
+
+/// Serves the control API at `path` until the listener fails, replacing a
+/// socket left behind by a previous run. Connections are handled concurrently.
+pub async fn serve(node: &Node, registry: &CommandRegistry, path: impl AsRef<Path>) -> Result<()> {
+    let path = path.as_ref();
+    match fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => fs::remove_file(path).map_err(Error::Io)?,
+        Ok(_) => return Err(Error::Config(format!("{} exists and is no socket", path.display()))),
+        Err(_) => {}
+    }
+    let listener = bind_private(path).await?;
+    info!("Control API listening on {}", path.display());
+
+    listener.incoming().for_each_concurrent(None, |stream| async move {
+        let result = match stream {
+            Ok(stream) => handle(node, registry, stream).await,
+            Err(err) => Err(err),
+        };
+        if let Err(err) = result {
+            warn!("Control connection failed: {err}");
+        }
+    }).await;
+    Ok(())
+}
+
+/// Binds a socket at `path` only our user may connect to, commands running
+/// with the node's rights. It is bound inside a fresh 0700 directory and
+/// moved into place once restricted, so nobody gets in between bind and chmod.
+async fn bind_private(path: &Path) -> Result<UnixListener> {
+    let name = path.file_name()
+        .ok_or_else(|| Error::Config(format!("{} is no socket path", path.display())))?;
+    let dir = path.with_file_name(format!(".{}.{}", name.to_string_lossy(), std::process::id()));
+    fs::DirBuilder::new().mode(0o700).create(&dir).map_err(Error::Io)?;
+    let staged = dir.join("socket");
+    let result: io::Result<UnixListener> = async {
+        let listener = UnixListener::bind(&staged).await?;
+        fs::set_permissions(&staged, fs::Permissions::from_mode(0o600))?;
+        fs::rename(&staged, path)?;
+        Ok(listener)
+    }.await;
+    let _ = fs::remove_file(&staged);
+    let _ = fs::remove_dir(&dir);
+    result.map_err(Error::Io)
+}
+
+async fn handle(node: &Node, registry: &CommandRegistry, stream: UnixStream) -> io::Result<()> {
+    let mut lines = BufReader::new(&stream).lines();
+    let mut writer = &stream;
+    while let Some(line) = lines.next().await {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = match serde_json::from_str(&line) {
+            Ok(request) => respond(node, registry, request).await,
+            Err(err) => ControlResponse::Error { message: format!("invalid request: {err}") },
+        };
+        let mut json = serde_json::to_string(&response).map_err(io::Error::from)?;
+        json.push('\n');
+        writer.write_all(json.as_bytes()).await?;
+        if let ControlResponse::Done { quit: true, .. } = response {
+            if let Err(err) = node.shutdown().await {
+                warn!("{err}");
+            }
+            break;
+        }
+    }
+    Ok(())
+}
+
+async fn respond(node: &Node, registry: &CommandRegistry, request: ControlRequest) -> ControlResponse {
+    let result = match request {
+        ControlRequest::Execute { line } => {
+            return match registry.execute(node, &line).await {
+                Ok(Outcome::Output(output)) => {
+                    ControlResponse::Done { quit: false, output: Some(output) }
+                }
+                Ok(outcome) => {
+                    ControlResponse::Done { quit: outcome == Outcome::Quit, output: None }
+                }
+                Err(err) => ControlResponse::Error { message: err.to_string() },
+            };
+        }
+        ControlRequest::Status => node.status().await.map(|status| ControlResponse::Status {
+            role: node.role().to_string(),
+            peer_id: status.peer_id.to_string(),
+            listen_addrs: status.listen_addrs.iter().map(ToString::to_string).collect(),
+            connected_peers: status.connected_peers.iter().map(ToString::to_string).collect(),
+        }),
+        ControlRequest::DirectoryPeers => node.list_peers().await.map(|entries| {
+            let peers = entries.into_iter()
+                .map(|entry| PeerEntry {
+                    peer_id: entry.peer_id.to_string(),
+                    username: entry.username,
+                    circuit_addr: entry.circuit_addr.to_string(),
+                })
+                .collect();
+            ControlResponse::DirectoryPeers { peers }
+        }),
+        ControlRequest::PeerConnections => node.peers().await.map(|peers| {
+            let connections = peers.into_iter()
+                .map(|peer| ConnectionEntry {
+                    peer_id: peer.peer_id.to_string(),
+                    username: peer.username,
+                    state: peer.state.to_string(),
+                })
+                .collect();
+            ControlResponse::PeerConnections { connections }
+        }),
+        ControlRequest::Reservations => node.reservations().await.map(|reservations| {
+            let reservations = reservations.into_iter()
+                .map(|reservation| ReservationEntry {
+                    peer_id: reservation.peer_id.to_string(),
+                    username: reservation.username,
+                    age_secs: reservation.age.as_secs(),
+                })
+                .collect();
+            ControlResponse::Reservations { reservations }
+        }),
+        ControlRequest::Circuits => node.circuits().await.map(|circuits| {
+            let circuits = circuits.into_iter()
+                .map(|circuit| CircuitEntry {
+                    src_peer_id: circuit.src_peer_id.to_string(),
+                    dst_peer_id: circuit.dst_peer_id.to_string(),
+                    age_secs: circuit.age.as_secs(),
+                })
+                .collect();
+            ControlResponse::Circuits { circuits }
+        }),
+    };
+    result.unwrap_or_else(|err| ControlResponse::Error { message: err.to_string() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{bind_private, ControlRequest, ControlResponse};
+    use async_std::task::block_on;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn wire_format() {
+        let request: ControlRequest = serde_json::from_str(r#"{"method":"execute","line":"dial bob"}"#).unwrap();
+        assert_eq!(request, ControlRequest::Execute { line: "dial bob".to_string() });
+        assert_eq!(serde_json::from_str::<ControlRequest>(r#"{"method":"status"}"#).unwrap(), ControlRequest::Status);
+
+        let response = ControlResponse::Done { quit: false, output: None };
+        assert_eq!(serde_json::to_string(&response).unwrap(), r#"{"result":"done","quit":false}"#);
+        let response = ControlResponse::Done { quit: false, output: Some("12D3KooW".to_string()) };
+        let json = r#"{"result":"done","quit":false,"output":"12D3KooW"}"#;
+        assert_eq!(serde_json::to_string(&response).unwrap(), json);
+        let request: ControlRequest = serde_json::from_str(r#"{"method":"directory_peers"}"#)
+            .unwrap();
+        assert_eq!(request, ControlRequest::DirectoryPeers);
+    }
+
+    #[test]
+    fn socket_is_private_from_the_start() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("node.sock");
+        let _listener = block_on(bind_private(&path)).unwrap();
+
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        // Only the socket is left, the staging directory is gone
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+}
@@ -0,0 +1,229 @@
+/// Keeps track of the display names peers advertise, and the hub's
+/// registration/lookup protocol for reserved clients
+
+use libp2p::{Multiaddr, PeerId};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+
+use crate::codec::JsonCodec;
+
+pub(crate) const DIRECTORY_PROTOCOL: &[u8] = b"/p2p_demo/directory/1.0.0";
+
+pub(crate) type DirectoryCodec = JsonCodec<DirectoryRequest, DirectoryResponse>;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum DirectoryRequest {
+    /// Publish the sender under `username`, reachable at `circuit_addr`.
+    Register { username: Option<String>, circuit_addr: String },
+    /// Every registered client.
+    List,
+    /// Clients whose username or PeerId equals `query`.
+    Lookup { query: String },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum DirectoryResponse {
+    Registered,
+    Entries(Vec<WireEntry>),
+    Error(String),
+}
+
+/// `DirectoryEntry` as sent over the wire
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WireEntry {
+    peer_id: String,
+    username: Option<String>,
+    circuit_addr: String,
+}
+
+/// A client registered on the hub
+#[derive(Debug, Clone, PartialEq)]
+pub struct DirectoryEntry {
+    pub peer_id: PeerId,
+    pub username: Option<String>,
+    pub circuit_addr: Multiaddr,
+}
+
+impl DirectoryEntry {
+    pub(crate) fn matches(&self, query: &str) -> bool {
+        self.username.as_deref() == Some(query) || self.peer_id.to_string() == query
+    }
+}
+
+impl From<&DirectoryEntry> for WireEntry {
+    fn from(entry: &DirectoryEntry) -> Self {
+        Self {
+            peer_id: entry.peer_id.to_string(),
+            username: entry.username.clone(),
+            circuit_addr: entry.circuit_addr.to_string(),
+        }
+    }
+}
+
+impl TryFrom<WireEntry> for DirectoryEntry {
+    type Error = String;
+
+    fn try_from(entry: WireEntry) -> Result<Self, String> {
+        Ok(Self {
+            peer_id: PeerId::from_str(&entry.peer_id).map_err(|err| err.to_string())?,
+            username: entry.username,
+            circuit_addr: Multiaddr::from_str(&entry.circuit_addr).map_err(|err| err.to_string())?,
+        })
+    }
+}
+
+const AGENT_NAME: &str = env!("CARGO_PKG_NAME");
+const AGENT_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Identify agent version advertising `username`, e.g. `p2p_demo/0.1.0/alice`.
+pub(crate) fn agent_version(username: Option<&str>) -> String {
+    match username {
+        Some(username) => format!("{AGENT_NAME}/{AGENT_VERSION}/{username}"),
+        None => format!("{AGENT_NAME}/{AGENT_VERSION}"),
+    }
+}
+
+/// Username carried by an agent version built with `agent_version`.
+pub(crate) fn username_from_agent_version(agent_version: &str) -> Option<&str> {
+    let rest = agent_version.strip_prefix(AGENT_NAME)?.strip_prefix('/')?;
+    let (_version, username) = rest.split_once('/')?;
+    Some(username).filter(|username| !username.is_empty())
+}
+
+/// Whether a peer other than `peer_id` registered `username` already.
+pub(crate) fn is_taken<'a>(
+    mut entries: impl Iterator<Item = &'a DirectoryEntry>,
+    peer_id: &PeerId,
+    username: &str,
+) -> bool {
+    entries.any(|entry| entry.peer_id != *peer_id && entry.username.as_deref() == Some(username))
+}
+
+/// Maps usernames to the peers that advertised them. The first peer to
+/// claim a name keeps it, unless the hub directory says otherwise.
+#[derive(Default)]
+pub(crate) struct PeerDirectory {
+    names: HashMap<PeerId, String>,
+    peers: HashMap<String, PeerId>,
+    /// Peers whose name came from the hub directory, claims can't change these
+    vouched: HashSet<PeerId>,
+}
+
+impl PeerDirectory {
+    /// Records a name `peer_id` advertised itself, refused when another peer
+    /// holds it or the hub directory knows `peer_id` by another name.
+    pub(crate) fn claim(&mut self, peer_id: PeerId, username: String) -> bool {
+        if self.vouched.contains(&peer_id) {
+            return self.names.get(&peer_id) == Some(&username);
+        }
+        match self.peers.get(&username) {
+            Some(holder) if *holder != peer_id => false,
+            _ => {
+                self.record(peer_id, username);
+                true
+            }
+        }
+    }
+
+    /// Records a name the hub directory vouches for, replacing other claims.
+    pub(crate) fn insert(&mut self, peer_id: PeerId, username: String) {
+        self.vouched.insert(peer_id);
+        self.record(peer_id, username);
+    }
+
+    fn record(&mut self, peer_id: PeerId, username: String) {
+        if let Some(previous) = self.names.insert(peer_id, username.clone()) {
+            if self.peers.get(&previous) == Some(&peer_id) {
+                self.peers.remove(&previous);
+            }
+        }
+        if let Some(holder) = self.peers.insert(username, peer_id) {
+            if holder != peer_id {
+                self.names.remove(&holder);
+            }
+        }
+    }
+
+    pub(crate) fn username(&self, peer_id: &PeerId) -> Option<&str> {
+        self.names.get(peer_id).map(String::as_str)
+    }
+
+    pub(crate) fn lookup(&self, username: &str) -> Option<PeerId> {
+        self.peers.get(username).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wire_entry_roundtrip() {
+        let entry = DirectoryEntry {
+            peer_id: PeerId::random(),
+            username: Some("alice".to_string()),
+            circuit_addr: "/ip4/127.0.0.1/tcp/8443/p2p-circuit".parse().unwrap(),
+        };
+        let wire = WireEntry::from(&entry);
+        assert_eq!(DirectoryEntry::try_from(wire).unwrap(), entry);
+        assert!(entry.matches("alice"));
+        assert!(entry.matches(&entry.peer_id.to_string()));
+        assert!(!entry.matches("bob"));
+    }
+
+    #[test]
+    fn agent_version_roundtrip() {
+        assert_eq!(username_from_agent_version(&agent_version(Some("alice"))), Some("alice"));
+        assert_eq!(username_from_agent_version(&agent_version(None)), None);
+        assert_eq!(username_from_agent_version("rust-libp2p/0.46.1"), None);
+    }
+
+    #[test]
+    fn rename_and_lookup() {
+        let mut directory = PeerDirectory::default();
+        let alice = PeerId::random();
+        directory.insert(alice, "alice".to_string());
+        assert_eq!(directory.lookup("alice"), Some(alice));
+
+        directory.insert(alice, "alicia".to_string());
+        assert_eq!(directory.lookup("alice"), None);
+        assert_eq!(directory.lookup("alicia"), Some(alice));
+        assert_eq!(directory.username(&alice), Some("alicia"));
+    }
+
+    #[test]
+    fn names_are_unique() {
+        let (alice, mallory) = (PeerId::random(), PeerId::random());
+        let mut directory = PeerDirectory::default();
+        assert!(directory.claim(alice, "alice".to_string()));
+        assert!(!directory.claim(mallory, "alice".to_string()));
+        assert_eq!(directory.lookup("alice"), Some(alice));
+        assert!(directory.claim(alice, "alice".to_string()));
+
+        let entry = DirectoryEntry {
+            peer_id: alice,
+            username: Some("alice".to_string()),
+            circuit_addr: "/ip4/127.0.0.1/tcp/8443/p2p-circuit".parse().unwrap(),
+        };
+        assert!(is_taken([&entry].into_iter(), &mallory, "alice"));
+        assert!(!is_taken([&entry].into_iter(), &alice, "alice"));
+        assert!(!is_taken([&entry].into_iter(), &mallory, "bob"));
+    }
+
+    #[test]
+    fn hub_entries_win_over_claims() {
+        let (bob, mallory) = (PeerId::random(), PeerId::random());
+        let mut directory = PeerDirectory::default();
+        // Mallory connected first, claiming Bob's name
+        assert!(directory.claim(mallory, "bob".to_string()));
+
+        directory.insert(bob, "bob".to_string());
+        assert_eq!(directory.lookup("bob"), Some(bob));
+        assert_eq!(directory.username(&mallory), None);
+        assert!(!directory.claim(mallory, "bob".to_string()));
+        // Nor can Bob be renamed by a claim
+        assert!(!directory.claim(bob, "robert".to_string()));
+        assert_eq!(directory.username(&bob), Some("bob"));
+    }
+}
@@ -0,0 +1,54 @@
+/// Defines errors surfaced by the crate
+
+use libp2p::swarm::DialError;
+use libp2p::core::transport::TransportError;
+use std::io;
+use thiserror::Error;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    /// Configuration could not be read or holds an invalid value.
+    #[error("config error: {0}")]
+    Config(String),
+    /// Key material could not be read or written: node identity, swarm key or TLS certificate and key.
+    #[error("key file error: {0}")]
+    Key(#[source] io::Error),
+    /// Transport failed to set up or to listen.
+    #[error("transport error: {0}")]
+    Transport(#[from] TransportError<io::Error>),
+    /// Dialing a peer failed.
+    #[error("dial error: {0}")]
+    Dial(#[from] DialError),
+    /// Relay refused or failed our reservation.
+    #[error("reservation error: {0}")]
+    Reservation(String),
+    /// No relay was reserved yet, run `relay <peerid>` first.
+    #[error("relay not found, reserve a relay first")]
+    NoRelay,
+    /// Peer failed or refused to answer a request.
+    #[error("request failed: {0}")]
+    Request(String),
+    /// File transfer can't go on.
+    #[error("transfer error: {0}")]
+    Transfer(String),
+    /// Local file could not be read or written.
+    #[error("io error: {0}")]
+    Io(#[source] io::Error),
+    /// Neither a PeerId nor the username of a known peer.
+    #[error("unknown peer {0}")]
+    UnknownPeer(String),
+    /// Swarm task has stopped and can't take commands anymore.
+    #[error("node has been shut down")]
+    Shutdown,
+    /// Swarm emitted an event the current step can't deal with.
+    #[error("protocol error: {0}")]
+    Protocol(String),
+}
+
+impl From<config::ConfigError> for Error {
+    fn from(e: config::ConfigError) -> Self {
+        Error::Config(e.to_string())
+    }
+}
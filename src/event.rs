@@ -1,10 +1,25 @@
 /// Defines events that Hub deals with
 
 use libp2p::identify::IdentifyEvent;
-use libp2p::ping::PingEvent;
+use libp2p::ping::{PingEvent, PingSuccess};
 use libp2p::relay::v2::relay::Event as RelayEvent;
 use libp2p::relay::v2::client::Event as RelayClientEvent;
 use libp2p::dcutr::behaviour::Event as DcutrEvent;
+use libp2p::request_response::{RequestResponseEvent, RequestResponseMessage};
+use libp2p::swarm::SwarmEvent;
+use libp2p::{Multiaddr, PeerId};
+use futures::channel::mpsc;
+use std::convert::Infallible;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::directory::{username_from_agent_version, DirectoryRequest, DirectoryResponse};
+use crate::chat::{ChatRequest, ChatResponse};
+use crate::transfer::{FileRequest, FileResponse};
+
+pub type DirectoryEvent = RequestResponseEvent<DirectoryRequest, DirectoryResponse>;
+pub type ChatEvent = RequestResponseEvent<ChatRequest, ChatResponse>;
+pub type FileEvent = RequestResponseEvent<FileRequest, FileResponse>;
 
 #[derive(Debug)]
 pub enum Event {
@@ -13,6 +28,9 @@ pub enum Event {
     Relay(RelayEvent),
     RelayClient(RelayClientEvent),
     Dcutr(DcutrEvent),
+    Directory(DirectoryEvent),
+    Chat(ChatEvent),
+    File(FileEvent),
 }
 
 impl From<PingEvent> for Event {
@@ -44,3 +62,243 @@ impl From<DcutrEvent> for Event {
         Event::Dcutr(e)
     }
 }
+
+impl From<DirectoryEvent> for Event {
+    fn from(e: DirectoryEvent) -> Self {
+        Event::Directory(e)
+    }
+}
+
+impl From<ChatEvent> for Event {
+    fn from(e: ChatEvent) -> Self {
+        Event::Chat(e)
+    }
+}
+
+// Behaviours that only watch connections emit nothing
+impl From<Infallible> for Event {
+    fn from(never: Infallible) -> Self {
+        match never {}
+    }
+}
+
+impl From<FileEvent> for Event {
+    fn from(e: FileEvent) -> Self {
+        Event::File(e)
+    }
+}
+
+/// Stable view of what happens on a `Node`, handed out by `Node::subscribe`
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum NodeEvent {
+    /// Started listening on `address`.
+    Listening { address: Multiaddr },
+    /// Stopped listening on `address`.
+    ListenerExpired { address: Multiaddr },
+    /// A connection to `peer_id` is open, `relayed` if it goes through a circuit.
+    ConnectionEstablished { peer_id: PeerId, address: Multiaddr, relayed: bool },
+    /// A connection closed, `num_established` connections to the peer remain.
+    ConnectionClosed { peer_id: PeerId, address: Multiaddr, num_established: u32 },
+    /// Peer identified itself, with its display name if it advertises one.
+    PeerIdentified {
+        peer_id: PeerId,
+        username: Option<String>,
+        agent_version: String,
+        listen_addrs: Vec<Multiaddr>,
+    },
+    /// Ping round trip to `peer_id` succeeded.
+    Ping { peer_id: PeerId, rtt: Duration },
+    /// Relay `relay_id` accepted or renewed our reservation.
+    ReservationAccepted { relay_id: PeerId, renewal: bool },
+    /// Relay `relay_id` failed our reservation.
+    ReservationFailed { relay_id: PeerId, renewal: bool, reason: String },
+    /// `src_peer_id` reached us through a relayed circuit.
+    InboundCircuitEstablished { src_peer_id: PeerId },
+    /// We reached a peer through a circuit on `relay_id`.
+    OutboundCircuitEstablished { relay_id: PeerId },
+    /// Hole punching with `peer_id` started.
+    DirectConnectionUpgradeStarted { peer_id: PeerId },
+    /// Hole punching succeeded, `peer_id` is directly connected.
+    DirectConnectionUpgradeSucceeded { peer_id: PeerId },
+    /// Hole punching failed, `peer_id` stays relayed.
+    DirectConnectionUpgradeFailed { peer_id: PeerId, reason: String },
+    /// `peer_id` sent us a direct message.
+    MessageReceived { peer_id: PeerId, text: String },
+    /// `peer_id` offers a file, accept or decline it by `transfer_id`.
+    FileOffered { peer_id: PeerId, transfer_id: u64, name: String, size: u64 },
+    /// `transferred` out of `size` bytes went through, in either direction.
+    FileProgress { peer_id: PeerId, transfer_id: u64, transferred: u64, size: u64 },
+    /// File from `peer_id` was verified and stored at `path`.
+    FileReceived { peer_id: PeerId, transfer_id: u64, path: PathBuf },
+    /// `peer_id` received and verified our file.
+    FileSent { peer_id: PeerId, transfer_id: u64 },
+    /// `peer_id` declined our offer.
+    FileDeclined { peer_id: PeerId, transfer_id: u64 },
+    /// Transfer stopped, incoming ones resume on the next accept.
+    FileFailed { peer_id: PeerId, transfer_id: u64, reason: String },
+    /// Hub accepted or renewed the reservation of `peer_id`.
+    RelayReservationAccepted { peer_id: PeerId, renewed: bool },
+    /// Hub let the reservation of `peer_id` expire.
+    RelayReservationTimedOut { peer_id: PeerId },
+    /// Hub relays a circuit from `src_peer_id` to `dst_peer_id`.
+    RelayCircuitAccepted { src_peer_id: PeerId, dst_peer_id: PeerId },
+    /// Hub closed the circuit from `src_peer_id` to `dst_peer_id`.
+    RelayCircuitClosed { src_peer_id: PeerId, dst_peer_id: PeerId },
+}
+
+impl NodeEvent {
+    pub(crate) fn from_swarm_event<E>(event: &SwarmEvent<Event, E>) -> Option<Self> {
+        match event {
+            SwarmEvent::NewListenAddr { address, .. } => {
+                Some(NodeEvent::Listening { address: address.clone() })
+            }
+            SwarmEvent::ExpiredListenAddr { address, .. } => {
+                Some(NodeEvent::ListenerExpired { address: address.clone() })
+            }
+            SwarmEvent::ConnectionEstablished { peer_id, endpoint, .. } => {
+                // Inbound circuits only show the relay in the local address
+                Some(NodeEvent::ConnectionEstablished {
+                    peer_id: *peer_id,
+                    address: endpoint.get_remote_address().clone(),
+                    relayed: endpoint.is_relayed(),
+                })
+            }
+            SwarmEvent::ConnectionClosed { peer_id, endpoint, num_established, .. } => {
+                Some(NodeEvent::ConnectionClosed {
+                    peer_id: *peer_id,
+                    address: endpoint.get_remote_address().clone(),
+                    num_established: *num_established,
+                })
+            }
+            SwarmEvent::Behaviour(event) => Self::from_event(event),
+            _ => None,
+        }
+    }
+
+    fn from_event(event: &Event) -> Option<Self> {
+        match event {
+            Event::Identify(IdentifyEvent::Received { peer_id, info }) => {
+                Some(NodeEvent::PeerIdentified {
+                    peer_id: *peer_id,
+                    username: username_from_agent_version(&info.agent_version).map(str::to_string),
+                    agent_version: info.agent_version.clone(),
+                    listen_addrs: info.listen_addrs.clone(),
+                })
+            }
+            Event::Ping(PingEvent { peer, result: Ok(PingSuccess::Ping { rtt }) }) => {
+                Some(NodeEvent::Ping { peer_id: *peer, rtt: *rtt })
+            }
+            Event::RelayClient(RelayClientEvent::ReservationReqAccepted { relay_peer_id, renewal, .. }) => {
+                Some(NodeEvent::ReservationAccepted { relay_id: *relay_peer_id, renewal: *renewal })
+            }
+            Event::RelayClient(RelayClientEvent::ReservationReqFailed { relay_peer_id, renewal, error }) => {
+                Some(NodeEvent::ReservationFailed {
+                    relay_id: *relay_peer_id,
+                    renewal: *renewal,
+                    reason: format!("{error:?}"),
+                })
+            }
+            Event::RelayClient(RelayClientEvent::InboundCircuitEstablished { src_peer_id, .. }) => {
+                Some(NodeEvent::InboundCircuitEstablished { src_peer_id: *src_peer_id })
+            }
+            Event::RelayClient(RelayClientEvent::OutboundCircuitEstablished { relay_peer_id, .. }) => {
+                Some(NodeEvent::OutboundCircuitEstablished { relay_id: *relay_peer_id })
+            }
+            Event::Dcutr(DcutrEvent::InitiatedDirectConnectionUpgrade { remote_peer_id, .. })
+            | Event::Dcutr(DcutrEvent::RemoteInitiatedDirectConnectionUpgrade { remote_peer_id, .. }) => {
+                Some(NodeEvent::DirectConnectionUpgradeStarted { peer_id: *remote_peer_id })
+            }
+            Event::Dcutr(DcutrEvent::DirectConnectionUpgradeSucceeded { remote_peer_id }) => {
+                Some(NodeEvent::DirectConnectionUpgradeSucceeded { peer_id: *remote_peer_id })
+            }
+            Event::Dcutr(DcutrEvent::DirectConnectionUpgradeFailed { remote_peer_id, error }) => {
+                Some(NodeEvent::DirectConnectionUpgradeFailed {
+                    peer_id: *remote_peer_id,
+                    reason: format!("{error:?}"),
+                })
+            }
+            Event::Chat(RequestResponseEvent::Message {
+                peer,
+                message: RequestResponseMessage::Request { request, .. },
+            }) => {
+                Some(NodeEvent::MessageReceived { peer_id: *peer, text: request.text.clone() })
+            }
+            Event::Relay(RelayEvent::ReservationReqAccepted { src_peer_id, renewed }) => {
+                Some(NodeEvent::RelayReservationAccepted { peer_id: *src_peer_id, renewed: *renewed })
+            }
+            Event::Relay(RelayEvent::ReservationTimedOut { src_peer_id }) => {
+                Some(NodeEvent::RelayReservationTimedOut { peer_id: *src_peer_id })
+            }
+            Event::Relay(RelayEvent::CircuitReqAccepted { src_peer_id, dst_peer_id }) => {
+                Some(NodeEvent::RelayCircuitAccepted { src_peer_id: *src_peer_id, dst_peer_id: *dst_peer_id })
+            }
+            Event::Relay(RelayEvent::CircuitClosed { src_peer_id, dst_peer_id, .. }) => {
+                Some(NodeEvent::RelayCircuitClosed { src_peer_id: *src_peer_id, dst_peer_id: *dst_peer_id })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Snapshot of a node's identity, listeners and connections
+#[derive(Debug, Clone, PartialEq)]
+pub struct Status {
+    pub peer_id: PeerId,
+    pub listen_addrs: Vec<Multiaddr>,
+    pub connected_peers: Vec<PeerId>,
+}
+
+impl Status {
+    pub(crate) fn of<B: libp2p::swarm::NetworkBehaviour>(swarm: &libp2p::Swarm<B>) -> Self {
+        Self {
+            peer_id: *swarm.local_peer_id(),
+            listen_addrs: swarm.listeners().cloned().collect(),
+            connected_peers: swarm.connected_peers().copied().collect(),
+        }
+    }
+}
+
+/// Fans `NodeEvent`s out to every live subscriber
+#[derive(Default)]
+pub(crate) struct Subscribers {
+    senders: Vec<mpsc::UnboundedSender<NodeEvent>>,
+}
+
+impl Subscribers {
+    pub(crate) fn subscribe(&mut self) -> mpsc::UnboundedReceiver<NodeEvent> {
+        let (sender, receiver) = mpsc::unbounded();
+        self.senders.push(sender);
+        receiver
+    }
+
+    pub(crate) fn publish(&mut self, event: NodeEvent) {
+        // Dropped receivers fail to take the event, forget them
+        self.senders.retain(|sender| sender.unbounded_send(event.clone()).is_ok());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{NodeEvent, Subscribers};
+    use futures::StreamExt;
+    use libp2p::Multiaddr;
+
+    #[test]
+    fn every_subscriber_gets_events() {
+        let mut subscribers = Subscribers::default();
+        let mut first = subscribers.subscribe();
+        let mut second = subscribers.subscribe();
+        let dropped = subscribers.subscribe();
+        drop(dropped);
+
+        let event = NodeEvent::Listening { address: "/ip4/127.0.0.1/tcp/8443".parse::<Multiaddr>().unwrap() };
+        subscribers.publish(event.clone());
+        assert_eq!(subscribers.senders.len(), 2);
+
+        futures::executor::block_on(async {
+            assert_eq!(first.next().await, Some(event.clone()));
+            assert_eq!(second.next().await, Some(event));
+        });
+    }
+}
@@ -13,9 +13,13 @@ use libp2p::{
     NetworkBehaviour,
     PeerId,
 };
-use libp2p::relay::v2::relay::Relay;
+use libp2p::relay::v2::relay::{Config as RelayConfig, Relay};
+use libp2p::request_response::{ProtocolSupport, RequestResponse};
+use std::iter;
 
 use crate::Event;
+use crate::codec::Protocol;
+use crate::directory::{DirectoryCodec, DIRECTORY_PROTOCOL};
 
 #[derive(NetworkBehaviour)]
 #[behaviour(out_event = "Event", event_process = false)]
@@ -23,17 +27,23 @@ pub struct Behaviour {
     relay: Relay,
     ping: Ping,
     identify: Identify,
+    pub(crate) directory: RequestResponse<DirectoryCodec>,
 }
 
 impl Behaviour {
-    pub fn new(public_key: PublicKey, peer_id: PeerId) -> Self {
+    pub fn new(public_key: PublicKey, peer_id: PeerId, relay_config: RelayConfig) -> Self {
         Self {
-            relay: Relay::new(peer_id, Default::default()),
+            relay: Relay::new(peer_id, relay_config),
             ping: Ping::new(PingConfig::new()),
             identify: Identify::new(IdentifyConfig::new(
                 "/TODO/0.0.1".to_string(),
                 public_key,
             )),
+            directory: RequestResponse::new(
+                DirectoryCodec::default(),
+                iter::once((Protocol(DIRECTORY_PROTOCOL), ProtocolSupport::Inbound)),
+                Default::default(),
+            ),
         }
     }
 }
@@ -0,0 +1,462 @@
+/// Drives the hub's swarm from a single task
+
+use libp2p::core::transport::{ListenerId, TransportError};
+use libp2p::swarm::{Swarm, SwarmEvent};
+use libp2p::relay::v2::relay::Event as RelayEventKinds;
+use libp2p::metrics::Metrics;
+use libp2p::request_response::{RequestResponseEvent, RequestResponseMessage, ResponseChannel};
+use libp2p::{Multiaddr, PeerId};
+use futures::channel::{mpsc, oneshot};
+use futures::future::FutureExt;
+use futures::stream::StreamExt;
+use futures::select;
+use futures_timer::Delay;
+use log::{info, debug, error, warn};
+use std::collections::{HashMap, HashSet};
+use std::fmt::Debug;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use super::behaviour::Behaviour;
+use super::{CircuitInfo, ReservationInfo};
+use crate::acl::{AccessList, AclWatch};
+use crate::error::{Error, Result};
+use crate::event::{NodeEvent, Status, Subscribers};
+use crate::metrics;
+use crate::Event::Relay as RelayEvent;
+use crate::Event::Ping as PingEvent;
+use crate::Event::Identify as IdentifyEvent;
+use crate::Event::Directory as DirectoryEvent;
+use crate::directory::{is_taken, DirectoryEntry, DirectoryRequest, DirectoryResponse};
+
+/// Requests sent from `Hub` to its swarm task
+pub(crate) enum Command {
+    Listen {
+        addr: Multiaddr,
+        reply: oneshot::Sender<Result<()>>,
+    },
+    ListPeers {
+        reply: oneshot::Sender<Vec<DirectoryEntry>>,
+    },
+    /// Re-read the ACL file right away.
+    ReloadAcl {
+        reply: oneshot::Sender<Result<()>>,
+    },
+    Reservations {
+        reply: oneshot::Sender<Vec<ReservationInfo>>,
+    },
+    Circuits {
+        reply: oneshot::Sender<Vec<CircuitInfo>>,
+    },
+    /// Close every connection of `peer_id`, it may come back.
+    Disconnect {
+        peer_id: PeerId,
+        reply: oneshot::Sender<Result<()>>,
+    },
+    /// Refuse `peer_id` until `Unban`, whatever the ACL says.
+    Ban {
+        peer_id: PeerId,
+        reply: oneshot::Sender<()>,
+    },
+    /// Lift an admin ban, fails if the ACL still refuses `peer_id`.
+    Unban {
+        peer_id: PeerId,
+        reply: oneshot::Sender<Result<()>>,
+    },
+    Status {
+        reply: oneshot::Sender<Status>,
+    },
+    Subscribe {
+        reply: oneshot::Sender<mpsc::UnboundedReceiver<NodeEvent>>,
+    },
+    Shutdown {
+        reply: oneshot::Sender<()>,
+    },
+}
+
+pub(crate) struct EventLoop {
+    swarm: Swarm<Behaviour>,
+    commands: mpsc::Receiver<Command>,
+    subscribers: Subscribers,
+    pending_listen: HashMap<ListenerId, oneshot::Sender<Result<()>>>,
+    /// Peers currently holding a reservation, since when
+    reserved: HashMap<PeerId, Instant>,
+    /// Open circuits, as source, destination and opening time
+    circuits: Vec<(PeerId, PeerId, Instant)>,
+    /// Reserved clients that registered themselves
+    registry: HashMap<PeerId, DirectoryEntry>,
+    acl_watch: AclWatch,
+    acl: AccessList,
+    /// Peers we banned, because the ACL denies them or an admin did
+    banned: HashSet<PeerId>,
+    /// Banned by an admin, ACL changes don't lift these
+    admin_bans: HashSet<PeerId>,
+    /// Prometheus metrics, when exported
+    metrics: Option<Metrics>,
+}
+
+/// How often the ACL file is checked for changes
+const ACL_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+impl EventLoop {
+    pub(crate) fn new(
+        swarm: Swarm<Behaviour>,
+        commands: mpsc::Receiver<Command>,
+        acl_watch: AclWatch,
+        acl: AccessList,
+        metrics: Option<Metrics>,
+    ) -> Self {
+        let mut event_loop = Self {
+            swarm,
+            commands,
+            subscribers: Subscribers::default(),
+            pending_listen: HashMap::new(),
+            reserved: HashMap::new(),
+            circuits: Vec::new(),
+            registry: HashMap::new(),
+            acl_watch,
+            acl: AccessList::default(),
+            banned: HashSet::new(),
+            admin_bans: HashSet::new(),
+            metrics,
+        };
+        event_loop.apply_acl(acl);
+        event_loop
+    }
+
+    pub(crate) async fn run(mut self) {
+        let mut acl_poll = Delay::new(ACL_POLL_INTERVAL).fuse();
+
+        loop { select! {
+            _ = acl_poll => {
+                if let Err(err) = self.reload_acl(false) {
+                    error!("Reloading ACL failed, keeping the previous rules: {err}");
+                }
+                acl_poll = Delay::new(ACL_POLL_INTERVAL).fuse();
+            },
+            event = self.swarm.select_next_some() => self.handle_swarm_event(event),
+            command = self.commands.next() => match command {
+                Some(Command::Shutdown { reply }) => {
+                    info!("Shutting down hub");
+                    let _ = reply.send(());
+                    break;
+                }
+                Some(command) => self.handle_command(command),
+                // All handles dropped, nobody can talk to us anymore
+                None => break,
+            },
+        } }
+    }
+
+    fn handle_command(&mut self, command: Command) {
+        match command {
+            Command::Listen { addr, reply } => {
+                match self.swarm.listen_on(addr) {
+                    Ok(listener_id) => {
+                        self.pending_listen.insert(listener_id, reply);
+                    }
+                    Err(err) => {
+                        let _ = reply.send(Err(err.into()));
+                    }
+                }
+            }
+            Command::ListPeers { reply } => {
+                let _ = reply.send(self.registry.values().cloned().collect());
+            }
+            Command::ReloadAcl { reply } => {
+                let _ = reply.send(self.reload_acl(true));
+            }
+            Command::Reservations { reply } => {
+                let now = Instant::now();
+                let reservations = self.reserved.iter()
+                    .map(|(peer_id, since)| ReservationInfo {
+                        peer_id: *peer_id,
+                        username: self.registry.get(peer_id).and_then(|entry| entry.username.clone()),
+                        age: now - *since,
+                    })
+                    .collect();
+                let _ = reply.send(reservations);
+            }
+            Command::Circuits { reply } => {
+                let now = Instant::now();
+                let circuits = self.circuits.iter()
+                    .map(|(src_peer_id, dst_peer_id, since)| CircuitInfo {
+                        src_peer_id: *src_peer_id,
+                        dst_peer_id: *dst_peer_id,
+                        age: now - *since,
+                    })
+                    .collect();
+                let _ = reply.send(circuits);
+            }
+            Command::Disconnect { peer_id, reply } => {
+                let result = self.swarm.disconnect_peer_id(peer_id)
+                    .map_err(|_| Error::UnknownPeer(peer_id.to_string()));
+                if result.is_ok() {
+                    info!("Disconnected {peer_id:?}");
+                }
+                let _ = reply.send(result);
+            }
+            Command::Ban { peer_id, reply } => {
+                self.admin_bans.insert(peer_id);
+                self.ban(peer_id, "banned by admin");
+                let _ = reply.send(());
+            }
+            Command::Unban { peer_id, reply } => {
+                self.admin_bans.remove(&peer_id);
+                if !self.acl.is_denied(&peer_id) && self.banned.remove(&peer_id) {
+                    info!("Unbanned {peer_id:?}");
+                    self.swarm.unban_peer_id(peer_id);
+                }
+                let result = if self.acl.is_allowed(&peer_id) {
+                    Ok(())
+                } else {
+                    Err(Error::Config(format!("the ACL still refuses {peer_id}")))
+                };
+                let _ = reply.send(result);
+            }
+            Command::Status { reply } => {
+                let _ = reply.send(Status::of(&self.swarm));
+            }
+            Command::Subscribe { reply } => {
+                let _ = reply.send(self.subscribers.subscribe());
+            }
+            Command::Shutdown { .. } => unreachable!("handled by the run loop"),
+        }
+    }
+
+    fn reload_acl(&mut self, force: bool) -> Result<()> {
+        if !force && !self.acl_watch.is_watching() {
+            return Ok(());
+        }
+        if let Some(acl) = self.acl_watch.load(force)? {
+            info!("ACL loaded");
+            self.apply_acl(acl);
+        }
+        Ok(())
+    }
+
+    /// Bans peers the ACL denies, disconnects those missing from its allowlist
+    /// and lifts bans it no longer calls for.
+    fn apply_acl(&mut self, acl: AccessList) {
+        let unbanned: Vec<PeerId> = self.banned.iter()
+            .filter(|peer| !acl.is_denied(peer) && !self.admin_bans.contains(peer))
+            .copied()
+            .collect();
+        for peer_id in unbanned {
+            info!("Unbanned {peer_id:?}");
+            self.banned.remove(&peer_id);
+            self.swarm.unban_peer_id(peer_id);
+        }
+        let denied: Vec<PeerId> = acl.denied().copied().collect();
+        let unlisted: Vec<PeerId> = self.swarm.connected_peers()
+            .filter(|peer| !acl.is_allowed(peer) && !acl.is_denied(peer))
+            .copied()
+            .collect();
+        self.acl = acl;
+        for peer_id in denied {
+            self.ban(peer_id, "denied by ACL");
+        }
+        for peer_id in unlisted {
+            self.refuse(peer_id);
+        }
+    }
+
+    /// Disconnects a peer missing from the allowlist without banning it, so
+    /// throwaway PeerIds don't pile up in the swarm's ban list.
+    fn refuse(&mut self, peer_id: PeerId) {
+        warn!("Disconnecting {peer_id:?}: not allowed by ACL");
+        let _ = self.swarm.disconnect_peer_id(peer_id);
+        self.forget(&peer_id);
+    }
+
+    /// Bans `peer_id`, closing its connections and with them its reservation and circuits.
+    fn ban(&mut self, peer_id: PeerId, reason: &str) {
+        if self.banned.insert(peer_id) {
+            warn!("Banned {peer_id:?}: {reason}");
+            self.swarm.ban_peer_id(peer_id);
+            self.forget(&peer_id);
+        }
+    }
+
+    fn forget(&mut self, peer_id: &PeerId) {
+        self.reserved.remove(peer_id);
+        if let Some(entry) = self.registry.remove(peer_id) {
+            info!("Unregistered {:?} ({:?})", entry.peer_id, entry.username);
+        }
+    }
+
+    fn handle_directory_request(
+        &mut self,
+        peer: PeerId,
+        request: DirectoryRequest,
+        channel: ResponseChannel<DirectoryResponse>,
+    ) {
+        let response = match request {
+            DirectoryRequest::Register { username, circuit_addr } => {
+                let taken = username.as_deref()
+                    .map_or(false, |username| is_taken(self.registry.values(), &peer, username));
+                if !self.reserved.contains_key(&peer) {
+                    DirectoryResponse::Error("Reserve a slot before registering".to_string())
+                } else if taken {
+                    warn!("Refused {peer:?} registering as {username:?}, held by another peer");
+                    DirectoryResponse::Error(format!("Username {:?} is taken", username.unwrap_or_default()))
+                } else {
+                    match Multiaddr::from_str(&circuit_addr) {
+                        Ok(circuit_addr) => {
+                            info!("Registered {peer:?} as {username:?} at {circuit_addr}");
+                            self.registry.insert(peer, DirectoryEntry { peer_id: peer, username, circuit_addr });
+                            DirectoryResponse::Registered
+                        }
+                        Err(err) => DirectoryResponse::Error(err.to_string()),
+                    }
+                }
+            }
+            DirectoryRequest::List => {
+                DirectoryResponse::Entries(self.registry.values().map(Into::into).collect())
+            }
+            DirectoryRequest::Lookup { query } => {
+                DirectoryResponse::Entries(
+                    self.registry.values()
+                        .filter(|entry| entry.matches(&query))
+                        .map(Into::into)
+                        .collect()
+                )
+            }
+        };
+        if self.swarm.behaviour_mut().directory.send_response(channel, response).is_err() {
+            warn!("Directory response to {peer:?} dropped, connection closed");
+        }
+    }
+
+    fn handle_swarm_event<E: Debug>(&mut self, event: SwarmEvent<crate::Event, E>) {
+        if let Some(metrics) = &self.metrics {
+            metrics::record(metrics, &event);
+        }
+        if let Some(node_event) = NodeEvent::from_swarm_event(&event) {
+            self.subscribers.publish(node_event);
+        }
+        match event {
+            SwarmEvent::Behaviour(RelayEvent(event)) => {
+                info!("Relay {:?}", event);
+                match event {
+                    RelayEventKinds::ReservationReqAccepted { src_peer_id, .. } => {
+                        // Renewals keep the age of the first reservation
+                        self.reserved.entry(src_peer_id).or_insert_with(Instant::now);
+                    }
+                    RelayEventKinds::CircuitReqAccepted { src_peer_id, dst_peer_id } => {
+                        self.circuits.push((src_peer_id, dst_peer_id, Instant::now()));
+                    }
+                    RelayEventKinds::CircuitClosed { src_peer_id, dst_peer_id, .. } => {
+                        if let Some(index) = self.circuits.iter()
+                            .position(|(src, dst, _)| *src == src_peer_id && *dst == dst_peer_id)
+                        {
+                            self.circuits.remove(index);
+                        }
+                    }
+                    RelayEventKinds::ReservationTimedOut { src_peer_id } => {
+                        self.forget(&src_peer_id);
+                    }
+                    RelayEventKinds::ReservationReqDenied { src_peer_id } => {
+                        warn!("Denied reservation of {src_peer_id:?}");
+                    }
+                    RelayEventKinds::CircuitReqDenied { src_peer_id, dst_peer_id } => {
+                        warn!("Denied circuit from {src_peer_id:?} to {dst_peer_id:?}");
+                    }
+                    _ => {}
+                }
+            }
+            SwarmEvent::Behaviour(DirectoryEvent(RequestResponseEvent::Message {
+                peer,
+                message: RequestResponseMessage::Request { request, channel, .. },
+            })) => {
+                self.handle_directory_request(peer, request, channel);
+            }
+            SwarmEvent::Behaviour(DirectoryEvent(event)) => {
+                debug!("Directory {:?}", event)
+            }
+            SwarmEvent::Behaviour(IdentifyEvent(event)) => {
+                debug!("Identify {:?}", event)
+            }
+            SwarmEvent::Behaviour(PingEvent(event)) => {
+                debug!("Ping {event:?}")
+            }
+            SwarmEvent::Behaviour(e) => {
+                info!("Event {:?}", e)
+            },
+            SwarmEvent::ConnectionEstablished {
+                peer_id, endpoint,
+                // num_established, concurrent_dial_errors
+                ..
+            } => {
+                debug!("Established connection to {peer_id:?}@{endpoint:?}");
+                // Denied peers are banned up front, those missing from an allowlist
+                // are dropped before they get a chance to reserve or open circuits
+                if !self.acl.is_allowed(&peer_id) {
+                    self.refuse(peer_id);
+                }
+            },
+            SwarmEvent::ConnectionClosed {
+                peer_id, endpoint, num_established, cause
+            } => {
+                error!("Connection with {peer_id:?}@{endpoint:?} closed due to {cause:?}");
+                if num_established == 0 {
+                    self.forget(&peer_id);
+                }
+            },
+            SwarmEvent::IncomingConnection { local_addr, send_back_addr } => {
+                debug!("Received connection from {send_back_addr} to {local_addr}");
+            },
+            SwarmEvent::IncomingConnectionError {
+                local_addr: _, send_back_addr, error
+            } => {
+                warn!("Incoming connection from {send_back_addr} error: {error}");
+            },
+            SwarmEvent::OutgoingConnectionError {
+                peer_id, error
+            } => {
+                error!("Outgoing connection error to {:?}: {:?}", peer_id, error);
+            },
+            SwarmEvent::BannedPeer {
+                peer_id, endpoint
+            } => {
+                warn!("Refused connection of banned peer {peer_id:?} from {}", endpoint.get_remote_address());
+            },
+            SwarmEvent::NewListenAddr {
+                listener_id, address
+            } => {
+                info!("Listening on {listener_id:?}@{address}");
+                if let Some(reply) = self.pending_listen.remove(&listener_id) {
+                    let _ = reply.send(Ok(()));
+                }
+            },
+            SwarmEvent::ExpiredListenAddr {
+                listener_id, address
+            } => {
+                info!("Stopped listening to {listener_id:?}@{address}");
+            },
+            SwarmEvent::ListenerClosed {
+                listener_id, addresses: _, reason
+            } => {
+                error!("Listener {listener_id:?} closed due to {reason:?}");
+                if let Some(reply) = self.pending_listen.remove(&listener_id) {
+                    let err = match reason {
+                        Err(error) => TransportError::Other(error).into(),
+                        Ok(()) => Error::Protocol("Listener closed before listening".to_string()),
+                    };
+                    let _ = reply.send(Err(err));
+                }
+            },
+            SwarmEvent::ListenerError {
+                listener_id, error
+            } => {
+                error!("Listener {listener_id:?} error: {error}");
+                if let Some(reply) = self.pending_listen.remove(&listener_id) {
+                    let _ = reply.send(Err(TransportError::Other(error).into()));
+                }
+            },
+            SwarmEvent::Dialing(peer_id) => {
+                debug!("Dialing {peer_id:?}");
+            },
+        }
+    }
+}
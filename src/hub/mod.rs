@@ -1,38 +1,83 @@
 /// Defines Hub
 
+use libp2p::core::either::EitherTransport;
+use libp2p::core::transport::OrTransport;
 use libp2p::core::upgrade;
+use libp2p::pnet::PnetConfig;
 use libp2p::tcp::TcpTransport;
 use libp2p::Transport;
 use libp2p::noise::NoiseConfig;
-use libp2p::swarm::{Swarm, SwarmEvent};
-use futures::future::FutureExt;
-use futures::stream::StreamExt;
-use log::{info, debug, error};
-use async_std::sync::{Arc, Mutex};
+use libp2p::websocket::WsConfig;
+use libp2p::swarm::Swarm;
+use libp2p::PeerId;
+use futures::channel::{mpsc, oneshot};
+use futures::sink::SinkExt;
+use async_std::sync::Mutex;
+use async_std::task::{self, JoinHandle};
+use std::path::PathBuf;
 use std::time::Duration;
-use futures::select;
 
 pub mod behaviour;
+mod event_loop;
 
 use super::conf;
 use super::keys::Keys;
+use crate::acl::AclWatch;
+use crate::command::{entry_lines, lines, Command as PromptCommand, CommandError, Outcome};
+use crate::error::{Error, Result};
+use crate::metrics::{self, Exporter};
+use crate::event::{NodeEvent, Status};
+use crate::directory::DirectoryEntry;
 use behaviour::Behaviour;
-use crate::Event::Relay as RelayEvent;
-use crate::Event::Ping as PingEvent;
-use crate::Event::Identify as IdentifyEvent;
+use event_loop::{Command, EventLoop};
+
+/// Reservation a client holds on us
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReservationInfo {
+    pub peer_id: PeerId,
+    /// Username it registered on the directory with
+    pub username: Option<String>,
+    pub age: Duration,
+}
+
+/// Circuit we relay from `src_peer_id` to `dst_peer_id`. There is no count of
+/// bytes relayed: the relay copies circuit data inside its connection handler,
+/// and libp2p 0.46 offers neither a per-circuit counter nor a hook to add one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CircuitInfo {
+    pub src_peer_id: PeerId,
+    pub dst_peer_id: PeerId,
+    pub age: Duration,
+}
 
 pub struct Hub {
     pub keys: Keys,
-    pub swarm: Arc<Mutex<Swarm<Behaviour>>>,
     conf: conf::Conf,
+    commands: mpsc::Sender<Command>,
+    task: Mutex<Option<JoinHandle<()>>>,
 }
 
 impl Hub {
-    pub fn new(conf: conf::Conf) -> Self {
-        let local_keys = Keys::new();
+    pub fn new(conf: conf::Conf) -> Result<Self> {
+        let local_keys = match conf.get_key_path() {
+            Some(path) => Keys::load_or_generate(path).map_err(Error::Key)?,
+            None => Keys::new(),
+        };
         let local_public_key = local_keys.key.public();
 
-        let tcp_transport = TcpTransport::default();
+        let mut ws_transport = WsConfig::new(TcpTransport::default());
+        if let Some(tls_config) = conf.get_tls_config()? {
+            ws_transport.set_tls_config(tls_config);
+        }
+        // WebSocket first, plain TCP would refuse `/ws` addresses anyway
+        let tcp_transport = OrTransport::new(ws_transport, TcpTransport::default());
+        // Peers without our key fail the pnet handshake, before noise even starts
+        let tcp_transport = match conf.get_psk()? {
+            Some(psk) => EitherTransport::Left(
+                tcp_transport.and_then(move |socket, _| PnetConfig::new(psk).handshake(socket))
+            ),
+            None => EitherTransport::Right(tcp_transport),
+        };
         let transport = tcp_transport
             .upgrade(upgrade::Version::V1)
             .authenticate(NoiseConfig::xx(local_keys.noise_key.clone()).into_authenticated())
@@ -40,118 +85,130 @@ impl Hub {
             .boxed();
         let swarm = Swarm::new(
             transport,
-            Behaviour::new(local_public_key, local_keys.peer_id),
+            Behaviour::new(local_public_key, local_keys.peer_id, conf.get_relay_config()),
             local_keys.peer_id,
         );
 
-        Self {
+        let mut acl_watch = AclWatch::new(conf.get_access_list()?, conf.get_acl_path().map(PathBuf::from));
+        // Fail closed, a hub missing the rules it was configured with doesn't start
+        let acl = acl_watch.load(true)?.unwrap_or_default();
+        let (exporter, metrics) = match conf.get_metrics_addr()? {
+            Some(addr) => Exporter::bind(addr).map(|(exporter, metrics)| (Some(exporter), Some(metrics)))?,
+            None => (None, None),
+        };
+        let (commands, receiver) = mpsc::channel(16);
+        let task = task::spawn(metrics::run(exporter, EventLoop::new(swarm, receiver, acl_watch, acl, metrics).run()));
+
+        Ok(Self {
             keys: local_keys,
-            swarm: Arc::new(Mutex::new(swarm)),
             conf: conf,
-        }
+            commands: commands,
+            task: Mutex::new(Some(task)),
+        })
     }
 
     pub fn set_peer_id(&mut self) {
         self.keys.peer_id = libp2p::PeerId::random();
     }
 
-    pub async fn bind(&self) {
-        let listen_addr = self.conf.get_bind_address();
-
-        let mut guard = self.swarm.lock_arc().await;
-        guard.listen_on(listen_addr).unwrap();
-        
-        // Wait to listen on all interfaces.
-        let mut delay = futures_timer::Delay::new(Duration::from_secs(1)).fuse();
-        loop {
-            futures::select! {
-                event = guard.next() => {
-                    match event.unwrap() {
-                        SwarmEvent::NewListenAddr { address, .. } => {
-                            info!("Listening on {:?}", address);
-                        }
-                        event => panic!("{:?}", event),
-                    }
-                }
-                _ = delay => {
-                    // Likely listening on all interfaces now, thus continuing by breaking the loop.
-                    break;
-                }
+    pub async fn bind(&self) -> Result<()> {
+        for addr in self.conf.get_bind_addresses() {
+            self.call(|reply| Command::Listen { addr, reply }).await??;
+        }
+        Ok(())
+    }
+
+    /// Lists the clients registered on our directory.
+    pub async fn list_peers(&self) -> Result<Vec<DirectoryEntry>> {
+        self.call(|reply| Command::ListPeers { reply }).await
+    }
+
+    /// Re-reads the ACL file and applies it to connected peers.
+    pub async fn reload_acl(&self) -> Result<()> {
+        self.call(|reply| Command::ReloadAcl { reply }).await?
+    }
+
+    /// Clients holding a reservation.
+    pub async fn reservations(&self) -> Result<Vec<ReservationInfo>> {
+        self.call(|reply| Command::Reservations { reply }).await
+    }
+
+    /// Circuits currently relayed.
+    pub async fn circuits(&self) -> Result<Vec<CircuitInfo>> {
+        self.call(|reply| Command::Circuits { reply }).await
+    }
+
+    /// Closes the connections of `peer_id`, dropping its reservation and circuits.
+    pub async fn disconnect(&self, peer_id: PeerId) -> Result<()> {
+        self.call(|reply| Command::Disconnect { peer_id, reply }).await?
+    }
+
+    /// Disconnects `peer_id` and refuses it until `unban`, ACL changes notwithstanding.
+    pub async fn ban(&self, peer_id: PeerId) -> Result<()> {
+        self.call(|reply| Command::Ban { peer_id, reply }).await
+    }
+
+    /// Lifts an admin ban, fails if the ACL still refuses `peer_id`.
+    pub async fn unban(&self, peer_id: PeerId) -> Result<()> {
+        self.call(|reply| Command::Unban { peer_id, reply }).await?
+    }
+
+    /// Our listen addresses and connected peers.
+    pub async fn status(&self) -> Result<Status> {
+        self.call(|reply| Command::Status { reply }).await
+    }
+
+    /// Runs an admin command, `Help` is answered by `CommandRegistry`.
+    pub async fn run(&self, command: PromptCommand) -> Result<Outcome, CommandError> {
+        match command {
+            PromptCommand::List => return Ok(entry_lines(self.list_peers().await?)),
+            PromptCommand::ReloadAcl => self.reload_acl().await?,
+            PromptCommand::Reservations => {
+                return Ok(lines(self.reservations().await?, "No reservation", |reservation| {
+                    let name = reservation.username.as_deref().unwrap_or("-");
+                    format!("{} {name} {}s", reservation.peer_id, reservation.age.as_secs())
+                }));
+            }
+            PromptCommand::Circuits => {
+                return Ok(lines(self.circuits().await?, "No circuit", |circuit| {
+                    let age = circuit.age.as_secs();
+                    format!("{} -> {} {age}s", circuit.src_peer_id, circuit.dst_peer_id)
+                }));
             }
+            PromptCommand::Kick { peer_id } => self.disconnect(peer_id).await?,
+            PromptCommand::Ban { peer_id } => self.ban(peer_id).await?,
+            PromptCommand::Unban { peer_id } => self.unban(peer_id).await?,
+            PromptCommand::ListenAddrs => {
+                let peer_id = self.keys.peer_id;
+                let addrs = self.status().await?.listen_addrs;
+                return Ok(lines(addrs, "Not listening", |addr| format!("{addr}/p2p/{peer_id}")));
+            }
+            PromptCommand::Quit => return Ok(Outcome::Quit),
+            command => return Err(CommandError::Unknown(format!("{command:?}"))),
         }
+        Ok(Outcome::Continue)
+    }
+
+    /// Streams every `NodeEvent` happening from now on.
+    pub async fn subscribe(&self) -> Result<mpsc::UnboundedReceiver<NodeEvent>> {
+        self.call(|reply| Command::Subscribe { reply }).await
+    }
+
+    pub async fn shutdown(&self) -> Result<()> {
+        self.call(|reply| Command::Shutdown { reply }).await
     }
 
-    // every loop lasts 100 micro seconds, preserves time for other tasks
+    /// Waits until the swarm task stops, i.e. after `shutdown`.
     pub async fn wait(&self) {
-        let mut guard = self.swarm.lock_arc().await;
-
-        let mut delay = futures_timer::Delay::new(Duration::from_micros(100)).fuse();
-        loop { select! {
-            event = guard.next() => { match event.unwrap() {
-                SwarmEvent::Behaviour(RelayEvent(event)) => {
-                    info!("Relay {:?}", event)
-                }
-                SwarmEvent::Behaviour(IdentifyEvent(event)) => {
-                    debug!("Identify {:?}", event)
-                }
-                SwarmEvent::Behaviour(PingEvent(event)) => {
-                    debug!("Ping {event:?}")
-                }
-                SwarmEvent::Behaviour(e) => {
-                    info!("Event {:?}", e)
-                },
-                SwarmEvent::ConnectionEstablished {
-                    peer_id, endpoint,
-                    // num_established, concurrent_dial_errors
-                    ..
-                } => {
-                    debug!("Established connection to {peer_id:?}@{endpoint:?}");
-                },
-                SwarmEvent::ConnectionClosed {
-                    peer_id, endpoint, num_established: _, cause
-                } => {
-                    error!("Connection with {peer_id:?}@{endpoint:?} closed due to {cause:?}");
-                },
-                SwarmEvent::IncomingConnection { local_addr, send_back_addr } => {
-                    debug!("Received connection from {send_back_addr} to {local_addr}");
-                },
-                SwarmEvent::IncomingConnectionError {
-                    // local_addr, send_back_addr, error
-                    ..
-                } => todo!(),
-                SwarmEvent::OutgoingConnectionError {
-                    peer_id, error
-                } => {
-                    error!("Outgoing connection error to {:?}: {:?}", peer_id, error);
-                },
-                SwarmEvent::BannedPeer {
-                    // peer_id, endpoint
-                    ..
-                } => todo!(),
-                SwarmEvent::NewListenAddr {
-                    listener_id, address
-                } => {
-                    info!("Listening on {listener_id:?}@{address}");
-                },
-                SwarmEvent::ExpiredListenAddr {
-                    listener_id, address
-                } => {
-                    info!("Stopped listening to {listener_id:?}@{address}");
-                },
-                SwarmEvent::ListenerClosed {
-                    // listener_id, addresses, reason
-                    ..
-                } => todo!(),
-                SwarmEvent::ListenerError {
-                    // listener_id, error
-                    ..
-                } => todo!(),
-                SwarmEvent::Dialing(_) => todo!(),
-            } }
-            _ = delay => {
-                // Timeout invoked, thus stop listening to swarm events
-                break;
-            }
-        } }
+        if let Some(task) = self.task.lock().await.take() {
+            task.await;
+        }
+    }
+
+    async fn call<T>(&self, command: impl FnOnce(oneshot::Sender<T>) -> Command) -> Result<T> {
+        let (reply, response) = oneshot::channel();
+        self.commands.clone().send(command(reply)).await
+            .map_err(|_| Error::Shutdown)?;
+        response.await.map_err(|_| Error::Shutdown)
     }
 }
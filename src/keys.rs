@@ -13,6 +13,16 @@ use libp2p::{
     PeerId,
 };
 use getrandom::getrandom;
+use hkdf::Hkdf;
+use sha2::Sha256;
+use log::info;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+use crate::error::{Error, Result};
+
+const SEED_KDF_SALT: &[u8] = b"p2p_demo/keys/v1";
 
 pub struct Keys {
     pub key: Keypair,
@@ -25,6 +35,10 @@ impl Keys {
         let mut secret_key_seed = [0u8; 32];
         GenKeyPair::generate_seed(&mut secret_key_seed[..]);
         let local_key = GenKeyPair::generate_ed25519(&mut secret_key_seed[..]);
+        Self::from_keypair(local_key)
+    }
+
+    pub fn from_keypair(local_key: Keypair) -> Self {
         let local_public_key = local_key.public();
         let local_peer_id = PeerId::from(local_public_key.clone());
 
@@ -38,6 +52,78 @@ impl Keys {
             noise_key: local_noise_key,
         }
     }
+
+    /// Derives the identity from a 32-byte secret seed, the same seed always
+    /// yields the same PeerId.
+    pub fn from_seed(mut seed: [u8; 32]) -> Self {
+        let local_key = GenKeyPair::generate_ed25519(&mut seed[..]);
+        Self::from_keypair(local_key)
+    }
+
+    /// Derives the identity from a seed given as 64 hex characters.
+    pub fn from_hex_seed(hex_seed: &str) -> Result<Self> {
+        let mut seed = [0u8; 32];
+        hex::decode_to_slice(hex_seed.trim(), &mut seed)
+            .map_err(|err| Error::Config(err.to_string() + " - seed must be 32 bytes of hex!"))?;
+        Ok(Self::from_seed(seed))
+    }
+
+    /// Derives the identity from a passphrase through HKDF-SHA256, meant for
+    /// reproducible test fleets rather than production secrets.
+    pub fn from_passphrase(passphrase: &str) -> Self {
+        let mut seed = [0u8; 32];
+        Hkdf::<Sha256>::new(Some(SEED_KDF_SALT), passphrase.as_bytes())
+            .expand(b"ed25519 identity", &mut seed)
+            .expect("32 bytes is a valid HKDF-SHA256 output length; qed");
+        Self::from_seed(seed)
+    }
+
+    /// Loads the keypair stored at `path`, or generates a fresh one and
+    /// saves it there when the file doesn't exist yet.
+    pub fn load_or_generate<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        if path.exists() {
+            let keys = Self::load(path)?;
+            info!("Loaded identity {} from {}", keys.peer_id, path.display());
+            Ok(keys)
+        } else {
+            let keys = Self::new();
+            keys.save(path)?;
+            info!("Generated identity {} into {}", keys.peer_id, path.display());
+            Ok(keys)
+        }
+    }
+
+    /// Reads a protobuf-encoded libp2p keypair from `path`.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let bytes = fs::read(path)?;
+        let key = Keypair::from_protobuf_encoding(&bytes)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        Ok(Self::from_keypair(key))
+    }
+
+    /// Writes the keypair protobuf-encoded to `path`, readable by the owner only.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let path = path.as_ref();
+        let bytes = self.key.to_protobuf_encoding()
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        if let Some(parent) = path.parent() {
+            if !parent.as_os_str().is_empty() {
+                fs::create_dir_all(parent)?;
+            }
+        }
+
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = options.open(path)?;
+        file.write_all(&bytes)?;
+        file.sync_all()
+    }
 }
 
 
@@ -46,33 +132,72 @@ pub trait GenKeyPair {
     fn generate_ed25519(&mut self) -> Keypair;
 }
 
-impl GenKeyPair for u8 {
+impl GenKeyPair for [u8] {
     fn generate_seed(&mut self) {
-        let mut seed = [0u8; 1];
-        if let Err(err) = getrandom(&mut seed) {
+        if let Err(err) = getrandom(self) {
             panic!("getrandom failed: {}", err);
         }
-        *self = seed[0];
     }
     fn generate_ed25519(&mut self) -> Keypair {
-        let mut bytes = [0u8; 32];
-        bytes[0] = *self;
-    
-        let secret_key = identity::ed25519::SecretKey::from_bytes(&mut bytes)
+        let secret_key = identity::ed25519::SecretKey::from_bytes(self)
             .expect("this returns `Err` only if the length is wrong; the length is correct; qed");
         Keypair::Ed25519(secret_key.into())
     }
 }
 
-impl GenKeyPair for [u8] {
-    fn generate_seed(&mut self) {
-        if let Err(err) = getrandom(self) {
-            panic!("getrandom failed: {}", err);
-        }
+#[cfg(test)]
+mod tests {
+    use super::Keys;
+
+    #[test]
+    fn same_seed_same_peer_id() {
+        let a = Keys::from_seed([7u8; 32]);
+        let b = Keys::from_seed([7u8; 32]);
+        assert_eq!(a.peer_id, b.peer_id);
     }
-    fn generate_ed25519(&mut self) -> Keypair {
-        let secret_key = identity::ed25519::SecretKey::from_bytes(self)
-            .expect("this returns `Err` only if the length is wrong; the length is correct; qed");
-        Keypair::Ed25519(secret_key.into())
+
+    #[test]
+    fn distinct_seeds_distinct_peer_ids() {
+        let mut seed = [0u8; 32];
+        let a = Keys::from_seed(seed);
+        // Seeds differing only past the first byte used to collapse together.
+        seed[31] = 1;
+        let b = Keys::from_seed(seed);
+        assert_ne!(a.peer_id, b.peer_id);
+    }
+
+    #[test]
+    fn hex_seed() {
+        let hex_seed = "01".repeat(32);
+        let a = Keys::from_hex_seed(&hex_seed).unwrap();
+        assert_eq!(a.peer_id, Keys::from_seed([1u8; 32]).peer_id);
+        assert!(Keys::from_hex_seed("0101").is_err());
+        assert!(Keys::from_hex_seed(&"zz".repeat(32)).is_err());
+    }
+
+    #[test]
+    fn passphrases() {
+        let alice = Keys::from_passphrase("alice");
+        assert_eq!(alice.peer_id, Keys::from_passphrase("alice").peer_id);
+        assert_ne!(alice.peer_id, Keys::from_passphrase("bob").peer_id);
+    }
+
+    #[test]
+    fn key_file_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("keys").join("node.key");
+
+        let generated = Keys::load_or_generate(&path).unwrap();
+        assert!(path.exists());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        let loaded = Keys::load_or_generate(&path).unwrap();
+        assert_eq!(loaded.peer_id, generated.peer_id);
+        assert_eq!(Keys::load(&path).unwrap().peer_id, generated.peer_id);
     }
 }
@@ -1,14 +1,31 @@
 ///
 
 use libp2p::PeerId;
+use futures::channel::mpsc;
+use std::path::Path;
 
 pub mod keys;
 pub mod conf;
+pub mod error;
+pub mod acl;
+pub mod command;
+mod metrics;
+#[cfg(unix)]
+pub mod control;
+pub use error::{Error, Result};
+pub use command::{Command, CommandError, CommandRegistry, CommandSpec, CustomCommand, Outcome};
 
 mod hub;
 mod client;
 mod event;
-pub use event::Event;
+mod directory;
+mod codec;
+mod chat;
+mod transfer;
+pub use directory::DirectoryEntry;
+pub use hub::{CircuitInfo, ReservationInfo};
+pub use client::{ConnectionState, PeerConnection};
+pub use event::{Event, NodeEvent, Status};
 
 pub enum Node {
     Hub(hub::Hub),
@@ -16,14 +33,21 @@ pub enum Node {
 }
 
 impl Node {
-    pub fn new(conf: conf::Conf) -> Self {
+    pub fn new(conf: conf::Conf) -> Result<Self> {
         match conf.role.trim() {
-            "hub" => Node::Hub(hub::Hub::new(conf)),
-            "client" => Node::Client(client::Client::new(conf)),
-            _ => panic!("No such role!")
+            "hub" => Ok(Node::Hub(hub::Hub::new(conf)?)),
+            "client" => Ok(Node::Client(client::Client::new(conf)?)),
+            role => Err(Error::Config(format!("No such role: {role}"))),
         }
     }
     
+    pub fn role(&self) -> &'static str {
+        match self {
+            Node::Hub(_) => "hub",
+            Node::Client(_) => "client",
+        }
+    }
+
     pub fn get_peer_id(&self) -> PeerId {
         match self {
             Node::Hub(x) => x.keys.peer_id,
@@ -31,28 +55,162 @@ impl Node {
         }
     }
 
-    pub async fn bind(&self) {
+    pub async fn bind(&self) -> Result<()> {
         match self {
             Node::Hub(x) => x.bind().await,
             Node::Client(x) => x.bind().await,
-        };
+        }
     }
     
-    pub async fn execute(&self, user_input: String) -> Result<bool, String> {
+    /// Commands `execute` and `run` understand for this role.
+    pub fn commands(&self) -> &'static [CommandSpec] {
+        match self {
+            Node::Hub(_) => command::HUB_COMMANDS,
+            Node::Client(_) => command::CLIENT_COMMANDS,
+        }
+    }
+
+    /// Parses and runs a prompt line, see `CommandRegistry` to add commands.
+    pub async fn execute(&self, line: &str) -> Result<Outcome, CommandError> {
+        CommandRegistry::new().execute(self, line).await
+    }
+
+    /// Runs a parsed command.
+    pub async fn run(&self, command: Command) -> Result<Outcome, CommandError> {
+        match (self, command) {
+            (_, Command::Help { command }) => {
+                Ok(Outcome::Output(command::help(self.commands(), command.as_deref())?))
+            }
+            (Node::Hub(x), command) => x.run(command).await,
+            (Node::Client(x), command) => x.run(command).await,
+        }
+    }
+
+    /// Reserves a slot on the relay `relay_id`, clients only.
+    pub async fn relay(&self, relay_id: PeerId) -> Result<()> {
+        match self {
+            Node::Client(x) => x.relay(relay_id).await,
+            Node::Hub(_) => Err(Error::Config("Hub can't reserve on a relay".to_string())),
+        }
+    }
+
+    /// Reserves on the hubs named in the config, clients only.
+    pub async fn connect_hub(&self) -> Result<Vec<PeerId>> {
         match self {
-            Node::Client(x) => x.execute(user_input).await,
-            _ => unreachable!(),
+            Node::Client(x) => x.connect_hub().await,
+            Node::Hub(_) => Ok(Vec::new()),
         }
     }
 
+    /// Dials `peer_id` through the reserved relay, clients only.
+    pub async fn dial(&self, peer_id: PeerId) -> Result<()> {
+        match self {
+            Node::Client(x) => x.relay_peer(peer_id).await,
+            Node::Hub(_) => Err(Error::Config("Hub can't dial peers".to_string())),
+        }
+    }
+
+    /// Finds the PeerId of `peer`, a PeerId or a username, clients only.
+    pub async fn resolve(&self, peer: &str) -> Result<PeerId> {
+        match self {
+            Node::Client(x) => x.resolve(peer).await,
+            Node::Hub(_) => Err(Error::Config("Hub can't resolve peers".to_string())),
+        }
+    }
+
+    /// Subscribes to the node's events, every subscriber gets its own copy.
+    pub async fn subscribe(&self) -> Result<mpsc::UnboundedReceiver<NodeEvent>> {
+        match self {
+            Node::Hub(x) => x.subscribe().await,
+            Node::Client(x) => x.subscribe().await,
+        }
+    }
+
+    /// Sends a direct message to `peer_id`, clients only.
+    pub async fn send_message(&self, peer_id: PeerId, text: &str) -> Result<()> {
+        match self {
+            Node::Client(x) => x.send_message(peer_id, text).await,
+            Node::Hub(_) => Err(Error::Config("Hub can't send messages".to_string())),
+        }
+    }
+
+    /// Offers a file to `peer_id`, returns its transfer id, clients only.
+    pub async fn send_file(&self, peer_id: PeerId, path: impl AsRef<Path>) -> Result<u64> {
+        match self {
+            Node::Client(x) => x.send_file(peer_id, path).await,
+            Node::Hub(_) => Err(Error::Config("Hub can't send files".to_string())),
+        }
+    }
+
+    /// Accepts or resumes an offered file, clients only.
+    pub async fn accept_file(&self, transfer_id: u64) -> Result<()> {
+        match self {
+            Node::Client(x) => x.accept_file(transfer_id).await,
+            Node::Hub(_) => Err(Error::Config("Hub can't receive files".to_string())),
+        }
+    }
+
+    /// Declines an offered file, clients only.
+    pub async fn decline_file(&self, transfer_id: u64) -> Result<()> {
+        match self {
+            Node::Client(x) => x.decline_file(transfer_id).await,
+            Node::Hub(_) => Err(Error::Config("Hub can't receive files".to_string())),
+        }
+    }
+
+    /// Our listen addresses and connected peers.
+    pub async fn status(&self) -> Result<Status> {
+        match self {
+            Node::Hub(x) => x.status().await,
+            Node::Client(x) => x.status().await,
+        }
+    }
+
+    /// Connected peers and whether they're relayed or direct, clients only.
+    pub async fn peers(&self) -> Result<Vec<PeerConnection>> {
+        match self {
+            Node::Client(x) => x.peers().await,
+            Node::Hub(_) => Err(Error::Config("Hub doesn't hole punch, see circuits".to_string())),
+        }
+    }
+
+    /// Reservations clients hold on us, hubs only.
+    pub async fn reservations(&self) -> Result<Vec<ReservationInfo>> {
+        match self {
+            Node::Hub(x) => x.reservations().await,
+            Node::Client(_) => Err(Error::Config("Clients hold no reservations".to_string())),
+        }
+    }
+
+    /// Circuits we relay, hubs only.
+    pub async fn circuits(&self) -> Result<Vec<CircuitInfo>> {
+        match self {
+            Node::Hub(x) => x.circuits().await,
+            Node::Client(_) => Err(Error::Config("Clients relay no circuits".to_string())),
+        }
+    }
+
+    /// Lists the clients registered on the hub directory.
+    pub async fn list_peers(&self) -> Result<Vec<DirectoryEntry>> {
+        match self {
+            Node::Hub(x) => x.list_peers().await,
+            Node::Client(x) => x.list_peers().await,
+        }
+    }
+
+    /// Stops the swarm task, pending requests resolve with `Error::Shutdown`.
+    pub async fn shutdown(&self) -> Result<()> {
+        match self {
+            Node::Hub(x) => x.shutdown().await,
+            Node::Client(x) => x.shutdown().await,
+        }
+    }
+
+    /// Waits until the node is shut down.
     pub async fn wait(&self) {
         match self {
-            Node::Hub(x) => {
-                x.wait().await;
-            }
-            Node::Client(x) => {
-                x.wait().await;
-            }
+            Node::Hub(x) => x.wait().await,
+            Node::Client(x) => x.wait().await,
         }
     }
 }
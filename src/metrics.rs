@@ -0,0 +1,100 @@
+/// Prometheus metrics of the swarm, served over plain HTTP at `/metrics`
+
+use async_std::io::BufReader;
+use async_std::net::{TcpListener, TcpStream};
+use futures::future::{self, Future};
+use futures::io::{AsyncBufReadExt, AsyncWriteExt};
+use futures::stream::StreamExt;
+use libp2p::metrics::{Metrics, Recorder};
+use libp2p::swarm::SwarmEvent;
+use log::{info, warn};
+use prometheus_client::encoding::text::encode;
+use prometheus_client::registry::Registry;
+use std::io;
+use std::net::SocketAddr;
+
+use crate::error::{Error, Result};
+use crate::Event;
+
+/// Serves the registry `Metrics` record into
+pub(crate) struct Exporter {
+    listener: TcpListener,
+    registry: Registry,
+}
+
+impl Exporter {
+    /// Binds `addr` right away, so a taken port fails at startup.
+    pub(crate) fn bind(addr: SocketAddr) -> Result<(Self, Metrics)> {
+        let mut registry = Registry::default();
+        let metrics = Metrics::new(&mut registry);
+        let listener = std::net::TcpListener::bind(addr).map_err(Error::Io)?;
+        info!("Serving metrics on http://{addr}/metrics");
+        Ok((Self { listener: listener.into(), registry }, metrics))
+    }
+
+    async fn serve(self) {
+        let registry = &self.registry;
+        self.listener.incoming().for_each_concurrent(None, |stream| async move {
+            let result = match stream {
+                Ok(stream) => respond(registry, stream).await,
+                Err(err) => Err(err),
+            };
+            if let Err(err) = result {
+                warn!("Metrics request failed: {err}");
+            }
+        }).await;
+    }
+}
+
+/// Runs `event_loop`, serving metrics alongside when exported, until the loop ends.
+pub(crate) async fn run(exporter: Option<Exporter>, event_loop: impl Future<Output = ()>) {
+    match exporter {
+        Some(exporter) => {
+            future::select(Box::pin(event_loop), Box::pin(exporter.serve())).await;
+        }
+        None => event_loop.await,
+    }
+}
+
+/// Records swarm events and those of the protocols libp2p has metrics for.
+pub(crate) fn record<E>(metrics: &Metrics, event: &SwarmEvent<Event, E>) {
+    metrics.record(event);
+    match event {
+        SwarmEvent::Behaviour(Event::Ping(event)) => metrics.record(event),
+        SwarmEvent::Behaviour(Event::Identify(event)) => metrics.record(event),
+        SwarmEvent::Behaviour(Event::Relay(event)) => metrics.record(event),
+        SwarmEvent::Behaviour(Event::Dcutr(event)) => metrics.record(event),
+        _ => {}
+    }
+}
+
+/// Answers one HTTP request, then closes the connection.
+async fn respond(registry: &Registry, stream: TcpStream) -> io::Result<()> {
+    let mut lines = BufReader::new(&stream).lines();
+    let request = lines.next().await.transpose()?.unwrap_or_default();
+    // Headers don't matter to us, but the client expects them read
+    while let Some(line) = lines.next().await {
+        if line?.is_empty() {
+            break;
+        }
+    }
+
+    let mut words = request.split_whitespace();
+    let (status, content_type, body) = match (words.next(), words.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            let mut body = Vec::new();
+            encode(&mut body, registry)?;
+            ("200 OK", "application/openmetrics-text; version=1.0.0; charset=utf-8", body)
+        }
+        (Some("GET"), _) => ("404 Not Found", "text/plain", b"Not found, try /metrics\n".to_vec()),
+        _ => ("405 Method Not Allowed", "text/plain", b"Only GET is supported\n".to_vec()),
+    };
+    let head = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len(),
+    );
+    let mut writer = &stream;
+    writer.write_all(head.as_bytes()).await?;
+    writer.write_all(&body).await?;
+    writer.flush().await
+}
@@ -0,0 +1,679 @@
+/// Chunked, resumable file transfer between connected clients
+///
+/// The sender offers a file, the receiver accepts or declines it and then
+/// pulls the content chunk by chunk, so an interrupted transfer resumes from
+/// what's already on disk. Content is verified against the offered SHA-256.
+
+use async_std::task;
+use futures::channel::{mpsc, oneshot};
+use getrandom::getrandom;
+use libp2p::request_response::{RequestId, RequestResponse, ResponseChannel};
+use libp2p::PeerId;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::codec::JsonCodec;
+use crate::error::{Error, Result};
+use crate::event::{NodeEvent, Subscribers};
+
+pub(crate) const FILE_PROTOCOL: &[u8] = b"/p2p_demo/file/1.0.0";
+
+/// Bytes asked for by a single `FileRequest::Read`
+const CHUNK_SIZE: u64 = 64 * 1024;
+
+pub(crate) type FileCodec = JsonCodec<FileRequest, FileResponse>;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum FileRequest {
+    /// Sender proposes a file, the receiver answers later by reading or declining.
+    Offer { transfer_id: u64, name: String, size: u64, sha256: String },
+    /// Receiver refuses the offer.
+    Decline { transfer_id: u64 },
+    /// Receiver pulls `len` bytes starting at `offset`.
+    Read { transfer_id: u64, offset: u64, len: u64 },
+    /// Receiver got everything, `verified` if the checksum matched.
+    Done { transfer_id: u64, verified: bool },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum FileResponse {
+    Ack,
+    Chunk {
+        #[serde(with = "hex")]
+        data: Vec<u8>,
+    },
+    Error(String),
+}
+
+/// Checksum and size of the file at `path`, hex encoded.
+pub(crate) fn hash_file(path: &Path) -> io::Result<(String, u64)> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let size = io::copy(&mut file, &mut hasher)?;
+    Ok((hex::encode(hasher.finalize()), size))
+}
+
+fn new_transfer_id() -> u64 {
+    let mut bytes = [0u8; 8];
+    if let Err(err) = getrandom(&mut bytes) {
+        panic!("getrandom failed: {}", err);
+    }
+    u64::from_be_bytes(bytes)
+}
+
+struct Outgoing {
+    peer_id: PeerId,
+    path: PathBuf,
+    size: u64,
+    /// Resolved once the receiver acknowledged the offer
+    reply: Option<oneshot::Sender<Result<u64>>>,
+}
+
+struct Incoming {
+    peer_id: PeerId,
+    name: String,
+    size: u64,
+    sha256: String,
+    /// Bytes already stored in the partial file
+    offset: u64,
+    hasher: Sha256,
+    part: Part,
+}
+
+/// The partial file of an incoming transfer
+enum Part {
+    /// Not accepted yet, or interrupted
+    Closed,
+    /// Opened or written to by a blocking task
+    Busy,
+    Open(File),
+}
+
+/// Outcome of file work done off the event loop, see `Transfers::complete`
+pub(crate) enum Completion {
+    Opened { transfer_id: u64, result: io::Result<(File, Sha256, u64)> },
+    Written { transfer_id: u64, file: File, len: u64, result: io::Result<()> },
+    Stored { transfer_id: u64, peer_id: PeerId, result: io::Result<PathBuf> },
+    Read {
+        peer_id: PeerId,
+        transfer_id: u64,
+        offset: u64,
+        channel: ResponseChannel<FileResponse>,
+        result: io::Result<Vec<u8>>,
+    },
+}
+
+/// Every transfer the client takes part in, both directions
+pub(crate) struct Transfers {
+    download_dir: PathBuf,
+    outgoing: HashMap<u64, Outgoing>,
+    incoming: HashMap<u64, Incoming>,
+    /// Requests in flight and the transfer they belong to
+    requests: HashMap<RequestId, u64>,
+    completed: mpsc::UnboundedSender<Completion>,
+    /// Polled by the event loop, fed by the blocking tasks
+    pub(crate) completions: mpsc::UnboundedReceiver<Completion>,
+}
+
+impl Transfers {
+    pub(crate) fn new(download_dir: PathBuf) -> Self {
+        let (completed, completions) = mpsc::unbounded();
+        Self {
+            download_dir,
+            outgoing: HashMap::new(),
+            incoming: HashMap::new(),
+            requests: HashMap::new(),
+            completed,
+            completions,
+        }
+    }
+
+    /// Offers the already hashed file at `path` to `peer_id`.
+    pub(crate) fn offer(
+        &mut self,
+        files: &mut RequestResponse<FileCodec>,
+        peer_id: PeerId,
+        path: PathBuf,
+        sha256: String,
+        size: u64,
+        reply: oneshot::Sender<Result<u64>>,
+    ) {
+        let name = match path.file_name().and_then(|name| name.to_str()) {
+            Some(name) => name.to_string(),
+            None => {
+                let _ = reply.send(Err(Error::Transfer(format!("{} is not a file", path.display()))));
+                return;
+            }
+        };
+        let transfer_id = new_transfer_id();
+        let request_id = files.send_request(&peer_id, FileRequest::Offer { transfer_id, name, size, sha256 });
+        self.requests.insert(request_id, transfer_id);
+        self.outgoing.insert(transfer_id, Outgoing { peer_id, path, size, reply: Some(reply) });
+    }
+
+    /// Starts or resumes receiving `transfer_id`, the partial file is opened off the event loop.
+    pub(crate) fn accept(&mut self, transfer_id: u64) -> Result<PeerId> {
+        let part_path = self.part_path(transfer_id)?;
+        let incoming = self.incoming.get_mut(&transfer_id)
+            .ok_or_else(|| Error::Transfer(format!("No offer {transfer_id}")))?;
+        if !matches!(incoming.part, Part::Closed) {
+            return Err(Error::Transfer(format!("Transfer {transfer_id} is already running")));
+        }
+        incoming.part = Part::Busy;
+        let size = incoming.size;
+        let peer_id = incoming.peer_id;
+        self.spawn(
+            move || open_part(&part_path, size),
+            move |result| Completion::Opened { transfer_id, result },
+        );
+        Ok(peer_id)
+    }
+
+    /// Refuses `transfer_id` and tells the sender.
+    pub(crate) fn decline(
+        &mut self,
+        files: &mut RequestResponse<FileCodec>,
+        transfer_id: u64,
+    ) -> Result<()> {
+        let part_path = self.part_path(transfer_id)?;
+        let incoming = self.forget_incoming(transfer_id)
+            .ok_or_else(|| Error::Transfer(format!("No offer {transfer_id}")))?;
+        let request_id = files.send_request(&incoming.peer_id, FileRequest::Decline { transfer_id });
+        self.requests.insert(request_id, transfer_id);
+        // Nobody waits for the cleanup
+        task::spawn_blocking(move || fs::remove_file(part_path));
+        Ok(())
+    }
+
+    pub(crate) fn handle_request(
+        &mut self,
+        files: &mut RequestResponse<FileCodec>,
+        subscribers: &mut Subscribers,
+        peer_id: PeerId,
+        request: FileRequest,
+        channel: ResponseChannel<FileResponse>,
+    ) {
+        let response = match request {
+            FileRequest::Offer { sha256, .. } if !is_sha256(&sha256) => {
+                FileResponse::Error("Checksum must be 64 hex characters".to_string())
+            }
+            FileRequest::Offer { transfer_id, .. } if self.incoming.contains_key(&transfer_id) => {
+                FileResponse::Error(format!("Transfer {transfer_id} is taken"))
+            }
+            FileRequest::Offer { transfer_id, name, size, sha256 } => {
+                info!("{peer_id:?} offers {name} ({size} bytes), `accept {transfer_id}` or `decline {transfer_id}`");
+                subscribers.publish(NodeEvent::FileOffered { peer_id, transfer_id, name: name.clone(), size });
+                self.incoming.insert(transfer_id, Incoming {
+                    peer_id,
+                    // Never let the sender pick a path outside the download directory
+                    name: sanitize(&name),
+                    size,
+                    // Compared against our own lowercase `hex::encode`
+                    sha256: sha256.to_ascii_lowercase(),
+                    offset: 0,
+                    hasher: Sha256::new(),
+                    part: Part::Closed,
+                });
+                FileResponse::Ack
+            }
+            FileRequest::Read { transfer_id, offset, len } => {
+                match self.outgoing.get(&transfer_id).filter(|outgoing| outgoing.peer_id == peer_id) {
+                    Some(outgoing) => {
+                        // Answered once the chunk is read, see `complete`
+                        let path = outgoing.path.clone();
+                        self.spawn(
+                            move || read_chunk(&path, offset, len.min(CHUNK_SIZE)),
+                            move |result| Completion::Read { peer_id, transfer_id, offset, channel, result },
+                        );
+                        return;
+                    }
+                    None => FileResponse::Error(format!("No transfer {transfer_id}")),
+                }
+            }
+            FileRequest::Decline { transfer_id } => {
+                if self.forget_outgoing(transfer_id, peer_id).is_some() {
+                    info!("{peer_id:?} declined transfer {transfer_id}");
+                    subscribers.publish(NodeEvent::FileDeclined { peer_id, transfer_id });
+                }
+                FileResponse::Ack
+            }
+            FileRequest::Done { transfer_id, verified } => {
+                if let Some(outgoing) = self.forget_outgoing(transfer_id, peer_id) {
+                    if verified {
+                        info!("Sent {} to {peer_id:?}", outgoing.path.display());
+                        subscribers.publish(NodeEvent::FileSent { peer_id, transfer_id });
+                    } else {
+                        warn!("{peer_id:?} failed to verify {}", outgoing.path.display());
+                        subscribers.publish(NodeEvent::FileFailed {
+                            peer_id,
+                            transfer_id,
+                            reason: "Checksum mismatch on the receiving side".to_string(),
+                        });
+                    }
+                }
+                FileResponse::Ack
+            }
+        };
+        if files.send_response(channel, response).is_err() {
+            warn!("File response to {peer_id:?} dropped, connection closed");
+        }
+    }
+
+    pub(crate) fn handle_response(
+        &mut self,
+        files: &mut RequestResponse<FileCodec>,
+        subscribers: &mut Subscribers,
+        request_id: RequestId,
+        response: FileResponse,
+    ) {
+        let transfer_id = match self.requests.remove(&request_id) {
+            Some(transfer_id) => transfer_id,
+            None => return,
+        };
+        match response {
+            FileResponse::Ack => {
+                if let Some(reply) = self.outgoing.get_mut(&transfer_id).and_then(|outgoing| outgoing.reply.take()) {
+                    let _ = reply.send(Ok(transfer_id));
+                }
+            }
+            FileResponse::Chunk { data } => {
+                if let Err(err) = self.write_chunk(transfer_id, data) {
+                    self.interrupt(subscribers, transfer_id, err.to_string());
+                }
+            }
+            FileResponse::Error(err) => {
+                if let Some(mut outgoing) = self.outgoing.remove(&transfer_id) {
+                    if let Some(reply) = outgoing.reply.take() {
+                        let _ = reply.send(Err(Error::Transfer(err)));
+                    }
+                } else {
+                    self.interrupt(subscribers, transfer_id, err);
+                }
+            }
+        }
+    }
+
+    /// A request failed on the way, the transfer stays resumable.
+    pub(crate) fn handle_failure(
+        &mut self,
+        subscribers: &mut Subscribers,
+        request_id: RequestId,
+        reason: String,
+    ) {
+        let transfer_id = match self.requests.remove(&request_id) {
+            Some(transfer_id) => transfer_id,
+            None => return,
+        };
+        if let Some(outgoing) = self.outgoing.get_mut(&transfer_id) {
+            if let Some(reply) = outgoing.reply.take() {
+                self.outgoing.remove(&transfer_id);
+                let _ = reply.send(Err(Error::Request(reason)));
+            }
+        } else {
+            self.interrupt(subscribers, transfer_id, reason);
+        }
+    }
+
+    /// Runs `work` on a blocking thread, the event loop gets its result as a `Completion`.
+    fn spawn<T: Send + 'static>(
+        &self,
+        work: impl FnOnce() -> T + Send + 'static,
+        done: impl FnOnce(T) -> Completion + Send + 'static,
+    ) {
+        let completed = self.completed.clone();
+        task::spawn(async move {
+            let result = task::spawn_blocking(work).await;
+            let _ = completed.unbounded_send(done(result));
+        });
+    }
+
+    /// Picks up where the blocking work of `spawn` left off.
+    pub(crate) fn complete(
+        &mut self,
+        files: &mut RequestResponse<FileCodec>,
+        subscribers: &mut Subscribers,
+        completion: Completion,
+    ) {
+        match completion {
+            Completion::Opened { transfer_id, result } => {
+                let incoming = match self.incoming.get_mut(&transfer_id) {
+                    Some(incoming) => incoming,
+                    None => return,
+                };
+                let (file, hasher, offset) = match result {
+                    Ok(opened) => opened,
+                    Err(err) => {
+                        self.interrupt(subscribers, transfer_id, err.to_string());
+                        return;
+                    }
+                };
+                if offset > 0 {
+                    info!("Resuming {} at {offset}/{} bytes", incoming.name, incoming.size);
+                }
+                incoming.offset = offset;
+                incoming.hasher = hasher;
+                incoming.part = Part::Open(file);
+                self.read_next_or_finish(files, subscribers, transfer_id);
+            }
+            Completion::Written { transfer_id, file, len, result } => {
+                let incoming = match self.incoming.get_mut(&transfer_id) {
+                    Some(incoming) if matches!(incoming.part, Part::Busy) => incoming,
+                    _ => return,
+                };
+                if let Err(err) = result {
+                    self.interrupt(subscribers, transfer_id, err.to_string());
+                    return;
+                }
+                incoming.offset += len;
+                incoming.part = Part::Open(file);
+                subscribers.publish(NodeEvent::FileProgress {
+                    peer_id: incoming.peer_id,
+                    transfer_id,
+                    transferred: incoming.offset,
+                    size: incoming.size,
+                });
+                self.read_next_or_finish(files, subscribers, transfer_id);
+            }
+            Completion::Stored { transfer_id, peer_id, result } => match result {
+                Ok(path) => {
+                    info!("Received {} from {peer_id:?}", path.display());
+                    subscribers.publish(NodeEvent::FileReceived { peer_id, transfer_id, path });
+                }
+                Err(err) => {
+                    warn!("Can't store transfer {transfer_id} from {peer_id:?}: {err}");
+                    subscribers.publish(NodeEvent::FileFailed { peer_id, transfer_id, reason: err.to_string() });
+                }
+            },
+            Completion::Read { peer_id, transfer_id, offset, channel, result } => {
+                let response = match result {
+                    Ok(data) => {
+                        if let Some(outgoing) = self.outgoing.get(&transfer_id) {
+                            subscribers.publish(NodeEvent::FileProgress {
+                                peer_id,
+                                transfer_id,
+                                transferred: offset + data.len() as u64,
+                                size: outgoing.size,
+                            });
+                        }
+                        FileResponse::Chunk { data }
+                    }
+                    Err(err) => FileResponse::Error(err.to_string()),
+                };
+                if files.send_response(channel, response).is_err() {
+                    warn!("File response to {peer_id:?} dropped, connection closed");
+                }
+            }
+        }
+    }
+
+    fn write_chunk(&mut self, transfer_id: u64, data: Vec<u8>) -> Result<()> {
+        let incoming = match self.incoming.get_mut(&transfer_id) {
+            Some(incoming) => incoming,
+            None => return Ok(()),
+        };
+        if data.is_empty() || incoming.offset + data.len() as u64 > incoming.size {
+            return Err(Error::Transfer("Sender returned a chunk of the wrong size".to_string()));
+        }
+        let mut file = match std::mem::replace(&mut incoming.part, Part::Busy) {
+            Part::Open(file) => file,
+            part => {
+                incoming.part = part;
+                return Err(Error::Transfer(format!("Transfer {transfer_id} isn't running")));
+            }
+        };
+        // A failed write interrupts the transfer, `accept` then hashes the file again
+        incoming.hasher.update(&data);
+        let len = data.len() as u64;
+        self.spawn(
+            move || {
+                let result = file.write_all(&data);
+                (file, result)
+            },
+            move |(file, result)| Completion::Written { transfer_id, file, len, result },
+        );
+        Ok(())
+    }
+
+    fn read_next_or_finish(
+        &mut self,
+        files: &mut RequestResponse<FileCodec>,
+        subscribers: &mut Subscribers,
+        transfer_id: u64,
+    ) {
+        match self.incoming.get(&transfer_id) {
+            Some(incoming) if incoming.offset < incoming.size => self.read_next(files, transfer_id),
+            Some(_) => self.finish(files, subscribers, transfer_id),
+            None => {}
+        }
+    }
+
+    fn finish(
+        &mut self,
+        files: &mut RequestResponse<FileCodec>,
+        subscribers: &mut Subscribers,
+        transfer_id: u64,
+    ) {
+        let part_path = match self.part_path(transfer_id) {
+            Ok(part_path) => part_path,
+            Err(_) => return,
+        };
+        let incoming = match self.forget_incoming(transfer_id) {
+            Some(incoming) => incoming,
+            None => return,
+        };
+        drop(incoming.part);
+        let peer_id = incoming.peer_id;
+        let verified = matches_checksum(incoming.hasher, &incoming.sha256);
+        let request_id = files.send_request(&peer_id, FileRequest::Done { transfer_id, verified });
+        self.requests.insert(request_id, transfer_id);
+
+        if verified {
+            let download_dir = self.download_dir.clone();
+            self.spawn(
+                move || store(&part_path, &download_dir, &incoming.name),
+                move |result| Completion::Stored { transfer_id, peer_id, result },
+            );
+        } else {
+            task::spawn_blocking(move || fs::remove_file(part_path));
+            warn!("Checksum mismatch on {} from {peer_id:?}, discarded", incoming.name);
+            subscribers.publish(NodeEvent::FileFailed {
+                peer_id,
+                transfer_id,
+                reason: "Checksum mismatch".to_string(),
+            });
+        }
+    }
+
+    fn read_next(&mut self, files: &mut RequestResponse<FileCodec>, transfer_id: u64) {
+        if let Some(incoming) = self.incoming.get(&transfer_id) {
+            let len = (incoming.size - incoming.offset).min(CHUNK_SIZE);
+            let request_id = files.send_request(
+                &incoming.peer_id,
+                FileRequest::Read { transfer_id, offset: incoming.offset, len },
+            );
+            self.requests.insert(request_id, transfer_id);
+        }
+    }
+
+    /// Pauses an incoming transfer, `accept` resumes it from the partial file.
+    fn interrupt(&mut self, subscribers: &mut Subscribers, transfer_id: u64, reason: String) {
+        if let Some(incoming) = self.incoming.get_mut(&transfer_id) {
+            incoming.part = Part::Closed;
+            warn!("Transfer {transfer_id} of {} interrupted: {reason}, `accept {transfer_id}` resumes it", incoming.name);
+            subscribers.publish(NodeEvent::FileFailed { peer_id: incoming.peer_id, transfer_id, reason });
+        }
+    }
+
+    /// Drops a finished or declined incoming transfer, late answers to its requests are ignored.
+    fn forget_incoming(&mut self, transfer_id: u64) -> Option<Incoming> {
+        self.requests.retain(|_, id| *id != transfer_id);
+        self.incoming.remove(&transfer_id)
+    }
+
+    /// Drops an outgoing transfer `peer_id` finished or declined.
+    fn forget_outgoing(&mut self, transfer_id: u64, peer_id: PeerId) -> Option<Outgoing> {
+        match self.outgoing.get(&transfer_id) {
+            Some(outgoing) if outgoing.peer_id == peer_id => {
+                self.requests.retain(|_, id| *id != transfer_id);
+                self.outgoing.remove(&transfer_id)
+            }
+            _ => None,
+        }
+    }
+
+    fn part_path(&self, transfer_id: u64) -> Result<PathBuf> {
+        let incoming = self.incoming.get(&transfer_id)
+            .ok_or_else(|| Error::Transfer(format!("No offer {transfer_id}")))?;
+        // One per transfer, concurrent transfers of the same content don't share it
+        Ok(self.download_dir.join(format!(".{}.{transfer_id}.part", incoming.sha256)))
+    }
+}
+
+/// Opens the partial file at `part_path`, hashing what a previous attempt
+/// left in it. Returns the file positioned for appending, hasher and offset.
+fn open_part(part_path: &Path, size: u64) -> io::Result<(File, Sha256, u64)> {
+    if let Some(dir) = part_path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut file = OpenOptions::new().read(true).append(true).create(true).open(part_path)?;
+    let mut hasher = Sha256::new();
+    file.seek(SeekFrom::Start(0))?;
+    let offset = io::copy(&mut (&mut file).take(size), &mut hasher)?;
+    Ok((file, hasher, offset))
+}
+
+/// Moves the verified `part_path` to `name` in `dir`. An existing file is
+/// never replaced, the name gets a ` (n)` suffix instead.
+fn store(part_path: &Path, dir: &Path, name: &str) -> io::Result<PathBuf> {
+    let (stem, extension) = match name.rfind('.') {
+        Some(dot) if dot > 0 => name.split_at(dot),
+        _ => (name, ""),
+    };
+    for n in 0.. {
+        let path = match n {
+            0 => dir.join(name),
+            n => dir.join(format!("{stem} ({n}){extension}")),
+        };
+        // Claiming the name first keeps a file showing up meanwhile from being replaced
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(_) => {
+                fs::rename(part_path, &path)?;
+                return Ok(path);
+            }
+            Err(err) if err.kind() == ErrorKind::AlreadyExists => continue,
+            Err(err) => return Err(err),
+        }
+    }
+    unreachable!("ran out of suffixes for {name}")
+}
+
+fn read_chunk(path: &Path, offset: u64, len: u64) -> io::Result<Vec<u8>> {
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(offset))?;
+    let mut data = Vec::with_capacity(len as usize);
+    file.take(len).read_to_end(&mut data)?;
+    Ok(data)
+}
+
+/// `sha256` being lowercase hex, as offers are normalized to.
+fn matches_checksum(hasher: Sha256, sha256: &str) -> bool {
+    hex::encode(hasher.finalize()) == sha256
+}
+
+fn is_sha256(checksum: &str) -> bool {
+    checksum.len() == 64 && checksum.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Keeps only the last path component of a name picked by a remote peer.
+fn sanitize(name: &str) -> String {
+    let name = name.rsplit(|c| c == '/' || c == '\\').next().unwrap_or_default();
+    match name {
+        "" | "." | ".." => "download".to_string(),
+        name => name.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{matches_checksum, open_part, sanitize, store, Incoming, Part, Transfers};
+    use libp2p::PeerId;
+    use sha2::{Digest, Sha256};
+    use std::fs;
+    use std::io::Write;
+
+    #[test]
+    fn remote_names_stay_in_download_dir() {
+        assert_eq!(sanitize("report.pdf"), "report.pdf");
+        assert_eq!(sanitize("../../etc/passwd"), "passwd");
+        assert_eq!(sanitize("C:\\Users\\x\\a.txt"), "a.txt");
+        assert_eq!(sanitize(".."), "download");
+        assert_eq!(sanitize("dir/"), "download");
+    }
+
+    #[test]
+    fn resumes_from_partial_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let part_path = dir.path().join(".abc.part");
+        fs::write(&part_path, b"hello").unwrap();
+
+        let (mut file, mut hasher, offset) = open_part(&part_path, 11).unwrap();
+        assert_eq!(offset, 5);
+        file.write_all(b" world").unwrap();
+        hasher.update(b" world");
+        assert_eq!(fs::read(&part_path).unwrap(), b"hello world");
+        assert!(matches_checksum(hasher, &hex::encode(Sha256::digest(b"hello world"))));
+    }
+
+    #[test]
+    fn detects_checksum_mismatch() {
+        let dir = tempfile::tempdir().unwrap();
+        let part_path = dir.path().join(".abc.part");
+        fs::write(&part_path, b"hello there").unwrap();
+
+        let (_, hasher, offset) = open_part(&part_path, 11).unwrap();
+        assert_eq!(offset, 11);
+        assert!(!matches_checksum(hasher, &hex::encode(Sha256::digest(b"hello world"))));
+    }
+
+    #[test]
+    fn never_overwrites() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.txt"), b"mine").unwrap();
+        fs::write(dir.path().join("a (1).txt"), b"mine too").unwrap();
+        let part_path = dir.path().join(".abc.part");
+        fs::write(&part_path, b"theirs").unwrap();
+
+        let path = store(&part_path, dir.path(), "a.txt").unwrap();
+        assert_eq!(path, dir.path().join("a (2).txt"));
+        assert_eq!(fs::read(&path).unwrap(), b"theirs");
+        assert_eq!(fs::read(dir.path().join("a.txt")).unwrap(), b"mine");
+        assert!(!part_path.exists());
+    }
+
+    #[test]
+    fn transfers_of_the_same_file_keep_apart() {
+        let mut transfers = Transfers::new("downloads".into());
+        let sha256 = hex::encode(Sha256::digest(b"hello world"));
+        for transfer_id in [1, 2] {
+            transfers.incoming.insert(transfer_id, Incoming {
+                peer_id: PeerId::random(),
+                name: "a.txt".to_string(),
+                size: 11,
+                sha256: sha256.clone(),
+                offset: 0,
+                hasher: Sha256::new(),
+                part: Part::Closed,
+            });
+        }
+        assert_ne!(transfers.part_path(1).unwrap(), transfers.part_path(2).unwrap());
+
+        assert!(transfers.forget_incoming(1).is_some());
+        assert!(transfers.part_path(1).is_err());
+        assert!(transfers.part_path(2).is_ok());
+    }
+}